@@ -0,0 +1,339 @@
+//! Generates msgpack-rpc glue for a trait of async methods.
+//!
+//! ```ignore
+//! #[rmp_futures_derive::service]
+//! pub trait Calc {
+//!     async fn add(&self, a: u32, b: u32) -> Result<u32, String>;
+//! }
+//! ```
+//!
+//! expands to the trait itself along with:
+//!
+//! - `CalcClient<W>`, wrapping an `RpcClient<W>` with an async method per
+//!   trait method that encodes the params, makes the call and decodes the
+//!   response straight from the connection into
+//!   `Result<Result<u32, String>, RpcError>`. Errors in the conventional
+//!   `[code, message, data]` encoding, like the ones `register_calc` sends
+//!   for bad params, are an `RpcError::Application` rather than a `String`.
+//! - `register_calc(&mut RpcServer<R, W>, Rc<impl Calc>)`, which adds a
+//!   handler per method. Params are decoded with `ArrayFuture::extract()`,
+//!   and requests with the wrong number of params or params of the wrong
+//!   type get an error response instead of reaching the trait.
+//!
+//! Every method must be `async`, take `&self` and return a `Result<T, E>`
+//! whose types implement `ToMsgPack` and `FromMsgPack`, as must the param
+//! types.
+
+extern crate proc_macro;
+
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Error, FnArg, GenericArgument, ItemTrait, PathArguments, ReturnType,
+    TraitItem, Type,
+};
+
+struct Method {
+    name: Ident,
+    args: Vec<(Ident, Type)>,
+    ret: Type,
+    ok: Type,
+    err: Type,
+}
+
+#[proc_macro_attribute]
+pub fn service(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let item = parse_macro_input!(item as ItemTrait);
+    let expanded = if !attr.is_empty() {
+        Err(Error::new(
+            TokenStream::from(attr).span(),
+            "service takes no arguments",
+        ))
+    } else {
+        expand(item)
+    };
+    expanded.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn expand(item: ItemTrait) -> syn::Result<TokenStream> {
+    if !item.generics.params.is_empty() {
+        return Err(Error::new(
+            item.generics.span(),
+            "service traits can't be generic",
+        ));
+    }
+    let methods = item
+        .items
+        .iter()
+        .filter_map(|i| match i {
+            TraitItem::Method(m) => Some(m),
+            _ => None,
+        })
+        .map(parse_method)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let client = client(&item, &methods);
+    let register = register(&item, &methods);
+    Ok(quote! {
+        #[allow(async_fn_in_trait)]
+        #item
+        #client
+        #register
+    })
+}
+
+fn parse_method(method: &syn::TraitItemMethod) -> syn::Result<Method> {
+    let sig = &method.sig;
+    if sig.asyncness.is_none() {
+        return Err(Error::new(sig.span(), "service methods must be async"));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "service methods can't be generic",
+        ));
+    }
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_none() => {}
+        _ => return Err(Error::new(sig.span(), "service methods must take &self")),
+    }
+    let args = inputs
+        .enumerate()
+        .map(|(i, arg)| match arg {
+            FnArg::Typed(arg) => Ok((
+                Ident::new(&format!("__arg{}", i), Span::call_site()),
+                (*arg.ty).clone(),
+            )),
+            FnArg::Receiver(r) => Err(Error::new(r.span(), "unexpected receiver")),
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+    // The largest tuple implementing `FromArray`
+    if args.len() > 8 {
+        return Err(Error::new(
+            sig.inputs.span(),
+            "service methods can take at most 8 params",
+        ));
+    }
+    let (ret, (ok, err)) = match &sig.output {
+        ReturnType::Type(_, ty) => match result_types(ty) {
+            Some(types) => ((**ty).clone(), types),
+            None => {
+                return Err(Error::new(
+                    ty.span(),
+                    "service methods must return a Result<T, E>",
+                ))
+            }
+        },
+        output => {
+            return Err(Error::new(
+                output.span(),
+                "service methods must return a Result<T, E>",
+            ))
+        }
+    };
+    Ok(Method {
+        name: sig.ident.clone(),
+        args,
+        ret,
+        ok,
+        err,
+    })
+}
+
+/// The `T` and `E` of a `Result<T, E>`. Aliases that fill in the error type,
+/// like `io::Result<T>`, are rejected, since the error type is needed to
+/// decode responses.
+fn result_types(ty: &Type) -> Option<(Type, Type)> {
+    let segment = match ty {
+        Type::Path(p) if p.qself.is_none() => p.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != "Result" {
+        return None;
+    }
+    let args = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => &args.args,
+        _ => return None,
+    };
+    let mut types = args.iter().map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    });
+    match (types.next(), types.next(), types.next()) {
+        (Some(Some(ok)), Some(Some(err)), None) => Some((ok, err)),
+        _ => None,
+    }
+}
+
+/// `CalcClient` for trait `Calc`
+fn client(item: &ItemTrait, methods: &[Method]) -> TokenStream {
+    let vis = &item.vis;
+    let ident = Ident::new(&format!("{}Client", item.ident), item.ident.span());
+    let doc = format!(
+        "Client stub for calling `{}` methods on the peer",
+        item.ident
+    );
+    let methods = methods.iter().map(|m| {
+        let name = &m.name;
+        let method = name.to_string();
+        let num_params = m.args.len() as u32;
+        let args = m.args.iter().map(|(arg, _)| arg);
+        let params = m.args.iter().map(|(arg, ty)| quote!(#arg: #ty));
+        let ret = &m.ret;
+        let (ok, err) = (&m.ok, &m.err);
+        quote! {
+            #vis async fn #name(
+                &self,
+                #(#params),*
+            ) -> ::std::result::Result<#ret, ::rmp_futures::rpc::error::RpcError> {
+                let (__params, __call) = self
+                    .client
+                    .call_typed::<#ok, #err>(#method, #num_params)
+                    .await?;
+                #(
+                    let __params =
+                        ::rmp_futures::rpc::service::write_param(__params, &#args).await?;
+                )*
+                __params.next().unwrap_end().release().await?;
+                ::rmp_futures::rpc::service::call_result(__call).await
+            }
+        }
+    });
+    quote! {
+        #[doc = #doc]
+        #vis struct #ident<W> {
+            client: ::rmp_futures::rpc::client::RpcClient<W>,
+        }
+
+        impl<W> #ident<W>
+        where
+            W: ::rmp_futures::rpc::service::AsyncWrite + Unpin,
+        {
+            #vis fn new(client: ::rmp_futures::rpc::client::RpcClient<W>) -> Self {
+                #ident { client }
+            }
+
+            #vis fn client(&self) -> &::rmp_futures::rpc::client::RpcClient<W> {
+                &self.client
+            }
+
+            #(#methods)*
+        }
+    }
+}
+
+/// `register_calc` for trait `Calc`
+fn register(item: &ItemTrait, methods: &[Method]) -> TokenStream {
+    let vis = &item.vis;
+    let trait_ident = &item.ident;
+    let ident = Ident::new(
+        &format!("register_{}", snake_case(&item.ident.to_string())),
+        item.ident.span(),
+    );
+    let doc = format!(
+        "Add a handler to `server` for each `{}` method, calling into `service`",
+        item.ident
+    );
+    let handlers = methods.iter().map(|m| {
+        let name = &m.name;
+        let method = name.to_string();
+        let args: Vec<_> = m.args.iter().map(|(arg, _)| arg).collect();
+        let tys = m.args.iter().map(|(_, ty)| ty);
+        quote! {
+            let __service = service.clone();
+            server.add_handler(
+                #method,
+                move |__params: ::rmp_futures::decode::ArrayFuture<R>,
+                      __responder: ::rmp_futures::rpc::shared::RpcResponder<W>|
+                      -> ::rmp_futures::rpc::server::HandlerFuture<R> {
+                    let __service = __service.clone();
+                    Box::pin(async move {
+                        let (__args, reader) = __params.extract::<(#(#tys,)*)>().await?;
+                        let (#(#args,)*) = match __args {
+                            Ok(args) => args,
+                            Err(e) => {
+                                let task = ::rmp_futures::rpc::service::invalid_params(__responder, e);
+                                return Ok((reader, task));
+                            }
+                        };
+                        let result = async move { __service.#name(#(#args),*).await };
+                        let task = ::rmp_futures::rpc::service::respond(__responder, result);
+                        Ok((reader, task))
+                    })
+                },
+            );
+        }
+    });
+    quote! {
+        #[doc = #doc]
+        #vis fn #ident<S, R, W>(
+            server: &mut ::rmp_futures::rpc::server::RpcServer<R, W>,
+            service: ::std::rc::Rc<S>,
+        ) where
+            S: #trait_ident + 'static,
+            R: ::rmp_futures::rpc::service::AsyncRead + Unpin + 'static,
+            W: ::rmp_futures::rpc::service::AsyncWrite + Unpin + 'static,
+        {
+            #(#handlers)*
+        }
+    }
+}
+
+/// Convert a CamelCase name to snake_case, keeping runs of capitals like
+/// `HTTP` in `HTTPService` together as one word
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+            let next = chars.get(i + 1);
+            // A word starts after a lowercase letter or digit, or at the last
+            // capital of a run that's followed by lowercase
+            let starts_word = match prev {
+                Some(p) if p.is_uppercase() => next.is_some_and(|n| n.is_lowercase()),
+                Some(_) => true,
+                None => false,
+            };
+            if starts_word {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snake() {
+        assert_eq!(snake_case("Calc"), "calc");
+        assert_eq!(snake_case("MyCalc"), "my_calc");
+        assert_eq!(snake_case("HTTPService"), "http_service");
+        assert_eq!(snake_case("MyHTTPService"), "my_http_service");
+        assert_eq!(snake_case("ServiceV2"), "service_v2");
+        assert_eq!(snake_case("RPC"), "rpc");
+    }
+
+    #[test]
+    fn result() {
+        let types = |ty| result_types(&syn::parse_str(ty).unwrap());
+        let (ok, err) = types("Result<u32, String>").unwrap();
+        assert_eq!(quote!(#ok).to_string(), "u32");
+        assert_eq!(quote!(#err).to_string(), "String");
+        assert!(types("std::result::Result<(), ()>").is_some());
+        assert!(types("io::Result<u32>").is_none());
+        assert!(types("Result").is_none());
+        assert!(types("Option<u32>").is_none());
+    }
+}
@@ -0,0 +1,200 @@
+#![feature(async_await)]
+
+use std::io::Cursor;
+use std::rc::Rc;
+
+use futures::executor::LocalPool;
+use futures::future;
+use futures::io::Result as IoResult;
+use rmp_futures::rpc::client::RpcClient;
+use rmp_futures::rpc::decode::{RpcMessage, RpcStream};
+use rmp_futures::rpc::error::{ApplicationError, RpcError};
+use rmp_futures::rpc::server::RpcServer;
+use rmp_futures::rpc::shared::SharedWriter;
+use rmpv::Value;
+
+#[rmp_futures_derive::service]
+pub trait Calc {
+    async fn add(&self, a: u32, b: u32) -> Result<u32, String>;
+    async fn greet(&self, name: String, shout: Option<bool>) -> Result<String, ()>;
+    async fn fail(&self) -> Result<(), String>;
+}
+
+/// Runs of capitals are one word in the name of `register_http_service`
+#[rmp_futures_derive::service]
+pub trait HTTPService {
+    async fn get(&self, path: String) -> Result<String, Vec<String>>;
+}
+
+struct Calculator;
+
+impl Calc for Calculator {
+    async fn add(&self, a: u32, b: u32) -> Result<u32, String> {
+        a.checked_add(b).ok_or_else(|| "overflow".to_string())
+    }
+
+    async fn greet(&self, name: String, shout: Option<bool>) -> Result<String, ()> {
+        let greeting = format!("hello {}", name);
+        Ok(if shout == Some(true) {
+            greeting.to_uppercase()
+        } else {
+            greeting
+        })
+    }
+
+    async fn fail(&self) -> Result<(), String> {
+        Err("failed".into())
+    }
+}
+
+fn to_stream(messages: &[Value]) -> RpcStream<Cursor<Vec<u8>>> {
+    let mut buf = Vec::new();
+    for m in messages {
+        rmpv::encode::write_value(&mut buf, m).unwrap();
+    }
+    RpcStream::new(Cursor::new(buf))
+}
+
+fn written(shared: &SharedWriter<Vec<u8>>) -> Vec<Value> {
+    let mut guard = LocalPool::new().run_until(shared.take()).unwrap();
+    let mut out = Cursor::new(std::mem::take(guard.get_mut()));
+    let mut values = Vec::new();
+    while (out.position() as usize) < out.get_ref().len() {
+        values.push(rmpv::decode::read_value(&mut out).unwrap());
+    }
+    values
+}
+
+fn request(id: u32, method: &str, params: Vec<Value>) -> Value {
+    Value::Array(vec![0.into(), id.into(), method.into(), params.into()])
+}
+
+fn response(id: u32, error: Value, result: Value) -> Value {
+    Value::Array(vec![1.into(), id.into(), error, result])
+}
+
+#[test]
+fn server() {
+    let mut server = RpcServer::new();
+    register_calc(&mut server, Rc::new(Calculator));
+
+    let requests = [
+        request(1, "add", vec![1.into(), 2.into()]),
+        request(2, "add", vec![u32::MAX.into(), 1.into()]),
+        request(3, "greet", vec!["bob".into(), true.into()]),
+        request(4, "greet", vec!["bob".into(), Value::Nil]),
+        request(5, "add", vec!["1".into(), 2.into()]),
+        request(6, "add", vec![1.into()]),
+        request(7, "fail", vec![]),
+    ];
+    let count = requests.len();
+    let shared = SharedWriter::new(Vec::new());
+
+    async fn serve(
+        server: &RpcServer<RpcStream<Cursor<Vec<u8>>>, Vec<u8>>,
+        mut stream: RpcStream<Cursor<Vec<u8>>>,
+        shared: &SharedWriter<Vec<u8>>,
+        count: usize,
+    ) -> IoResult<()> {
+        for _ in 0..count {
+            let (r, task) = match stream.next().await? {
+                RpcMessage::Request(req) => server.handle_request(req, shared).await?,
+                _ => panic!("Wrong message type"),
+            };
+            task.await?;
+            stream = r;
+        }
+        Ok(())
+    }
+    LocalPool::new()
+        .run_until(serve(&server, to_stream(&requests), &shared, count))
+        .unwrap();
+
+    assert_eq!(
+        written(&shared),
+        vec![
+            response(1, Value::Nil, 3.into()),
+            response(2, "overflow".into(), Value::Nil),
+            response(3, Value::Nil, "HELLO BOB".into()),
+            response(4, Value::Nil, "hello bob".into()),
+            response(
+                5,
+                ApplicationError::invalid_params("element 0: expected u32").to_value(),
+                Value::Nil
+            ),
+            response(
+                6,
+                ApplicationError::invalid_params("expected 2 elements, got 1").to_value(),
+                Value::Nil
+            ),
+            response(7, "failed".into(), Value::Nil),
+        ]
+    );
+}
+
+#[test]
+fn client() {
+    let shared = SharedWriter::new(Vec::new());
+    let client = RpcClient::new(shared.clone());
+    let calc = CalcClient::new(client.clone());
+    let http = HTTPServiceClient::new(client.clone());
+    let bad_params = ApplicationError::invalid_params("expected 2 elements, got 1");
+    let not_found = Value::Array(vec!["not".into(), "found".into()]);
+    let stream = to_stream(&[
+        response(0, Value::Nil, 3.into()),
+        response(1, "failed".into(), Value::Nil),
+        response(2, Value::Nil, "not a number".into()),
+        response(3, bad_params.to_value(), Value::Nil),
+        response(4, not_found.clone(), Value::Nil),
+    ]);
+
+    async fn read_responses(
+        client: RpcClient<Vec<u8>>,
+        mut stream: RpcStream<Cursor<Vec<u8>>>,
+    ) -> IoResult<()> {
+        for _ in 0..5 {
+            stream = match stream.next().await? {
+                RpcMessage::Response(resp) => client.handle_response(resp).await?,
+                _ => panic!("Wrong message type"),
+            };
+        }
+        Ok(())
+    }
+    let calls = future::join5(
+        calc.add(1, 2),
+        calc.fail(),
+        calc.add(3, 4),
+        calc.fail(),
+        http.get("/".into()),
+    );
+    let ((add, fail, bad, invalid, get), read) =
+        LocalPool::new().run_until(future::join(calls, read_responses(client, stream)));
+    read.unwrap();
+    assert_eq!(add.unwrap(), Ok(3));
+    assert_eq!(fail.unwrap(), Err("failed".to_string()));
+    match bad {
+        Err(RpcError::Decode(e)) => assert_eq!(e.expected(), "u32"),
+        r => panic!("unexpected {:?}", r),
+    }
+    // A conventional error isn't mistaken for the method's own error type
+    match invalid {
+        Err(RpcError::Application(e)) => assert_eq!(e, bad_params),
+        r => panic!("unexpected {:?}", r),
+    }
+    // Unless it doesn't fit the conventional encoding
+    assert_eq!(
+        get.unwrap(),
+        Err(vec!["not".to_string(), "found".to_string()])
+    );
+
+    assert_eq!(
+        written(&shared),
+        vec![
+            request(0, "add", vec![1.into(), 2.into()]),
+            request(1, "fail", vec![]),
+            request(2, "add", vec![3.into(), 4.into()]),
+            request(3, "fail", vec![]),
+            request(4, "get", vec!["/".into()]),
+        ]
+    );
+}
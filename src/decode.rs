@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -10,6 +11,7 @@ use futures::io::ErrorKind;
 use futures::io::Result as IoResult;
 use futures::prelude::*;
 
+use crate::encode::MsgPackSink;
 use crate::MsgPackOption;
 
 #[derive(Debug)]
@@ -187,20 +189,36 @@ impl<R: AsyncRead + Unpin> MsgPackFuture<R> {
         Ok(BigEndian::read_f64(&self.read_8().await?))
     }
 
-    pub async fn skip(self) -> IoResult<R> {
-        let val = self.decode().await?;
-        Ok(match val {
-            ValueFuture::Nil(r) => r,
-            ValueFuture::Boolean(_b, r) => r,
-            ValueFuture::Integer(_i, r) => r,
-            ValueFuture::F32(_f, r) => r,
-            ValueFuture::F64(_f, r) => r,
-            ValueFuture::Array(a) => a.skip().boxed_local().await?,
-            ValueFuture::Map(m) => m.skip().boxed_local().await?,
-            ValueFuture::Bin(m) => m.skip().await?,
-            ValueFuture::String(s) => s.skip().await?,
-            ValueFuture::Ext(e) => e.skip().await?,
-        })
+    /// Consume the entire message, including nested elements, and return the
+    /// underlying reader.
+    ///
+    /// Nested collections are counted rather than recursed into. Recursing
+    /// would instantiate `skip` for a new reader type at each level without
+    /// end, and would use memory for each level of nesting the peer sends.
+    pub async fn skip(mut self) -> IoResult<R> {
+        let mut remaining = 1usize;
+        while remaining > 0 {
+            remaining -= 1;
+            match MsgPackFuture::new(&mut self.reader).decode().await? {
+                ValueFuture::Nil(_)
+                | ValueFuture::Boolean(..)
+                | ValueFuture::Integer(..)
+                | ValueFuture::F32(..)
+                | ValueFuture::F64(..) => {}
+                ValueFuture::Array(a) => remaining += a.len(),
+                ValueFuture::Map(m) => remaining += 2 * m.len(),
+                ValueFuture::Bin(b) => {
+                    b.skip().await?;
+                }
+                ValueFuture::String(s) => {
+                    s.skip().await?;
+                }
+                ValueFuture::Ext(e) => {
+                    e.skip().await?;
+                }
+            }
+        }
+        Ok(self.reader)
     }
 
     pub async fn decode(mut self) -> IoResult<ValueFuture<R>> {
@@ -392,11 +410,28 @@ impl<R: AsyncRead + Unpin> MsgPackFuture<R> {
 
     /// Read an entire message into a heap-allocated dynamic `Value`
     pub async fn into_value(self) -> IoResult<(Value, R)>
+    where
+        R: 'static,
+    {
+        self.decode().await?.into_value().await
+    }
+
+    /// Write the entire message to `writer` as it's read, without buffering
+    /// it, and return the underlying reader
+    pub async fn copy_to<W: AsyncWrite + Unpin>(self, writer: &mut W) -> IoResult<R> {
+        self.decode().await?.copy_to(writer).await
+    }
+}
+
+impl<R: AsyncRead + Unpin> ValueFuture<R> {
+    /// Read the rest of an already-decoded message into a heap-allocated
+    /// dynamic `Value`
+    pub async fn into_value(self) -> IoResult<(Value, R)>
     where
         R: 'static,
     {
         // Boxing the future is necessary for array and map which recurse.
-        Ok(match self.decode().await? {
+        Ok(match self {
             ValueFuture::Nil(r) => (Value::Nil, r),
             ValueFuture::Boolean(b, r) => (Value::Boolean(b), r),
             ValueFuture::Integer(i, r) => (Value::Integer(i), r),
@@ -409,6 +444,135 @@ impl<R: AsyncRead + Unpin> MsgPackFuture<R> {
             ValueFuture::Ext(e) => e.into_value().await?,
         })
     }
+
+    /// Consume the rest of an already-decoded message and return the
+    /// underlying reader
+    pub async fn skip(self) -> IoResult<R> {
+        match self {
+            ValueFuture::Nil(r)
+            | ValueFuture::Boolean(_, r)
+            | ValueFuture::Integer(_, r)
+            | ValueFuture::F32(_, r)
+            | ValueFuture::F64(_, r) => Ok(r),
+            ValueFuture::Array(a) => a.skip().await,
+            ValueFuture::Map(m) => m.skip().await,
+            ValueFuture::Bin(b) => b.skip().await,
+            ValueFuture::String(s) => s.skip().await,
+            ValueFuture::Ext(e) => e.skip().await,
+        }
+    }
+
+    /// Write the rest of an already-decoded message to `writer` as it's read,
+    /// without buffering it, and return the underlying reader. Headers are
+    /// re-encoded, so integers and lengths may be written in a different
+    /// width than they were read.
+    pub async fn copy_to<W: AsyncWrite + Unpin>(self, writer: &mut W) -> IoResult<R> {
+        let (mut reader, mut remaining) = self.copy_head(writer).await?;
+        // Like `MsgPackFuture::skip()`, nested elements are counted rather than
+        // recursed into
+        while remaining > 0 {
+            remaining -= 1;
+            let value = MsgPackFuture::new(&mut reader).decode().await?;
+            remaining += value.copy_head(writer).await?.1;
+        }
+        Ok(reader)
+    }
+
+    /// Copy a scalar, string, bin or ext value, or just the header of an array
+    /// or map. Returns the number of nested elements still to be copied.
+    async fn copy_head<W: AsyncWrite + Unpin>(self, writer: &mut W) -> IoResult<(R, usize)> {
+        let sink = MsgPackSink::new(&mut *writer);
+        Ok(match self {
+            ValueFuture::Nil(r) => {
+                sink.write_nil().await?;
+                (r, 0)
+            }
+            ValueFuture::Boolean(b, r) => {
+                sink.write_bool(b).await?;
+                (r, 0)
+            }
+            ValueFuture::Integer(i, r) => {
+                match i.as_u64() {
+                    Some(i) => sink.write_int(i).await?,
+                    None => sink.write_int(i.as_i64().unwrap()).await?,
+                };
+                (r, 0)
+            }
+            ValueFuture::F32(f, r) => {
+                sink.write_f32(f).await?;
+                (r, 0)
+            }
+            ValueFuture::F64(f, r) => {
+                sink.write_f64(f).await?;
+                (r, 0)
+            }
+            ValueFuture::Array(a) => {
+                sink.write_array_len(a.len as u32).await?;
+                (a.reader, a.len)
+            }
+            ValueFuture::Map(m) => {
+                sink.write_map_len(m.len as u32).await?;
+                (m.reader, 2 * m.len)
+            }
+            ValueFuture::Bin(b) => {
+                sink.write_bin_len(b.len as u32).await?;
+                (copy_bytes(b, writer).await?, 0)
+            }
+            ValueFuture::String(s) => {
+                sink.write_str_len(s.len as u32).await?;
+                (copy_bytes(s.0, writer).await?, 0)
+            }
+            ValueFuture::Ext(e) => {
+                writer.write_all(&ext_meta(e.len as u32, e.ty)).await?;
+                (copy_bytes(e.bin, writer).await?, 0)
+            }
+        })
+    }
+}
+
+/// Encode the header of an ext value. Unlike `MsgPackSink::write_ext_meta()`
+/// this accepts the negative types reserved by the spec, so they can be
+/// passed through.
+fn ext_meta(len: u32, ty: i8) -> Vec<u8> {
+    let mut meta = Vec::with_capacity(6);
+    match len {
+        1 => meta.push(Marker::FixExt1.to_u8()),
+        2 => meta.push(Marker::FixExt2.to_u8()),
+        4 => meta.push(Marker::FixExt4.to_u8()),
+        8 => meta.push(Marker::FixExt8.to_u8()),
+        16 => meta.push(Marker::FixExt16.to_u8()),
+        len if len <= 0xff => meta.extend(&[Marker::Ext8.to_u8(), len as u8]),
+        len if len <= 0xffff => {
+            meta.push(Marker::Ext16.to_u8());
+            meta.extend(&(len as u16).to_be_bytes());
+        }
+        len => {
+            meta.push(Marker::Ext32.to_u8());
+            meta.extend(&len.to_be_bytes());
+        }
+    }
+    meta.push(ty as u8);
+    meta
+}
+
+/// Copy the contents of a string, bin or ext to a writer
+async fn copy_bytes<R, W>(bin: BinFuture<R>, writer: &mut W) -> IoResult<R>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let BinFuture {
+        mut reader,
+        mut len,
+    } = bin;
+    let mut buf = [0; 256];
+    while len > 0 {
+        let n = std::cmp::min(len, buf.len());
+        reader.read_exact(&mut buf[..n]).await?;
+        writer.write_all(&buf[..n]).await?;
+        len -= n;
+    }
+    Ok(reader)
 }
 
 #[derive(Debug)]
@@ -470,6 +634,13 @@ impl<R: AsyncRead + Unpin> ArrayFuture<R> {
         }
     }
 
+    /// Decode the remaining elements as a tuple, checking that there are
+    /// exactly as many as the tuple has fields. On a mismatch, the rest of the
+    /// array is skipped.
+    pub async fn extract<T: FromArray>(self) -> IoResult<(Result<T, ExtractError>, R)> {
+        T::from_array(self).await
+    }
+
     pub async fn into_value(self) -> IoResult<(Value, R)>
     where
         R: 'static,
@@ -745,6 +916,45 @@ impl<R: AsyncRead + Unpin> StringFuture<R> {
         self.0.read_all(buf).await
     }
 
+    /// Compare the string against each entry of `table` as it is read,
+    /// without allocating, and return the index of the entry it matches, if
+    /// any. The string is always consumed entirely.
+    pub async fn match_table(self, table: &[&str]) -> IoResult<(Option<usize>, R)> {
+        let BinFuture { mut reader, len } = self.0;
+        let mut candidate = table.iter().position(|s| s.len() == len);
+        let mut buf = [0; 32];
+        let mut pos = 0;
+        while pos < len {
+            let n = std::cmp::min(len - pos, buf.len());
+            reader.read_exact(&mut buf[..n]).await?;
+            let chunk = &buf[..n];
+            // Earlier entries have already been ruled out, and later ones are
+            // only candidates if they share the prefix matched so far
+            candidate = candidate.and_then(|c| {
+                let matched = &table[c].as_bytes()[..pos];
+                (c..table.len()).find(|&i| {
+                    let s = table[i].as_bytes();
+                    s.len() == len && &s[..pos] == matched && &s[pos..pos + n] == chunk
+                })
+            });
+            pos += n;
+        }
+        Ok((candidate, reader))
+    }
+
+    /// Read up to `max` bytes of the string at once and look for the first
+    /// `sep` byte among them. Returns the bytes read, which may go past the
+    /// separator, where the separator is if it was found, and the rest of
+    /// the string.
+    pub async fn read_until(self, sep: u8, max: usize) -> IoResult<(Vec<u8>, Option<usize>, Self)> {
+        let mut buf = vec![0; self.len().min(max)];
+        let BinFuture { mut reader, len } = self.0;
+        reader.read_exact(&mut buf).await?;
+        let found = buf.iter().position(|&b| b == sep);
+        let len = len - buf.len();
+        Ok((buf, found, StringFuture(BinFuture { reader, len })))
+    }
+
     pub async fn skip(self) -> IoResult<R> {
         self.0.skip().await
     }
@@ -815,6 +1025,255 @@ impl<R> DerefMut for ExtFuture<R> {
     }
 }
 
+/// A message didn't have the type it was being decoded as. The message has
+/// been skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeError {
+    expected: &'static str,
+}
+
+impl TypeError {
+    pub fn new(expected: &'static str) -> Self {
+        TypeError { expected }
+    }
+
+    /// Name of the type that was expected
+    pub fn expected(&self) -> &'static str {
+        self.expected
+    }
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "expected {}", self.expected)
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// Skip a message of the wrong type
+pub(crate) async fn mismatch<T, R: AsyncRead + Unpin>(
+    value: ValueFuture<R>,
+    expected: &'static str,
+) -> IoResult<(Result<T, TypeError>, R)> {
+    value
+        .skip()
+        .await
+        .map(|r| (Err(TypeError::new(expected)), r))
+}
+
+/// Types that can be decoded from a single msgpack message. A message of the
+/// wrong type is skipped and reported as a `TypeError`, leaving the reader
+/// positioned after it, so the caller can carry on with the rest of the
+/// stream.
+#[allow(async_fn_in_trait)]
+pub trait FromMsgPack: Sized {
+    /// Decode the rest of a message whose type has already been read
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)>;
+
+    async fn from_msgpack<R: AsyncRead + Unpin>(
+        msg: MsgPackFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        Self::from_value_future(msg.decode().await?).await
+    }
+}
+
+/// Unit is decoded from nil
+impl FromMsgPack for () {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::Nil(r) => Ok((Ok(()), r)),
+            value => mismatch(value, "nil").await,
+        }
+    }
+}
+
+impl FromMsgPack for bool {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::Boolean(b, r) => Ok((Ok(b), r)),
+            value => mismatch(value, "bool").await,
+        }
+    }
+}
+
+macro_rules! from_msgpack_int {
+    ($as:ident: $($ty:ident),*) => {
+        $(
+            /// Integers are accepted regardless of their encoded width, as
+            /// long as the value is in range
+            impl FromMsgPack for $ty {
+                async fn from_value_future<R: AsyncRead + Unpin>(
+                    value: ValueFuture<R>,
+                ) -> IoResult<(Result<Self, TypeError>, R)> {
+                    match value {
+                        ValueFuture::Integer(i, r) => {
+                            let val = i.$as().and_then(|i| $ty::try_from(i).ok());
+                            Ok((val.ok_or_else(|| TypeError::new(stringify!($ty))), r))
+                        }
+                        value => mismatch(value, stringify!($ty)).await,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_msgpack_int!(as_u64: u8, u16, u32, u64);
+from_msgpack_int!(as_i64: i8, i16, i32, i64);
+
+impl FromMsgPack for f32 {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::F32(f, r) => Ok((Ok(f), r)),
+            value => mismatch(value, "f32").await,
+        }
+    }
+}
+
+/// Accepts both f32 and f64
+impl FromMsgPack for f64 {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::F32(f, r) => Ok((Ok(f.into()), r)),
+            ValueFuture::F64(f, r) => Ok((Ok(f), r)),
+            value => mismatch(value, "f64").await,
+        }
+    }
+}
+
+impl FromMsgPack for String {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::String(s) => s.into_string().await.map(|(s, r)| (Ok(s), r)),
+            value => mismatch(value, "string").await,
+        }
+    }
+}
+
+/// Nil is decoded as `None`
+impl<T: FromMsgPack> FromMsgPack for Option<T> {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::Nil(r) => Ok((Ok(None), r)),
+            value => T::from_value_future(value)
+                .await
+                .map(|(val, r)| (val.map(Some), r)),
+        }
+    }
+}
+
+/// Decoded from an array. If an element has the wrong type, the rest of the
+/// array is skipped.
+impl<T: FromMsgPack> FromMsgPack for Vec<T> {
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        let mut a = match value {
+            ValueFuture::Array(a) => a,
+            value => return mismatch(value, "array").await,
+        };
+        let mut vec = Vec::with_capacity(a.len());
+        loop {
+            match a.next() {
+                MsgPackOption::Some(m) => {
+                    let (elem, next) = T::from_msgpack(m).await?;
+                    match elem {
+                        Ok(elem) => vec.push(elem),
+                        Err(e) => return next.skip().await.map(|r| (Err(e), r)),
+                    }
+                    a = next;
+                }
+                MsgPackOption::End(r) => return Ok((Ok(vec), r)),
+            }
+        }
+    }
+}
+
+/// Why an array couldn't be decoded as a tuple. The rest of the array has
+/// been skipped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtractError {
+    /// The array has a different number of elements than the tuple
+    Length { expected: usize, actual: usize },
+    /// An element has the wrong type
+    Element { index: usize, error: TypeError },
+}
+
+impl std::fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExtractError::Length { expected, actual } => {
+                write!(f, "expected {} elements, got {}", expected, actual)
+            }
+            ExtractError::Element { index, error } => write!(f, "element {}: {}", index, error),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+/// Tuples that can be decoded from the elements of an array, in order
+#[allow(async_fn_in_trait)]
+pub trait FromArray: Sized {
+    async fn from_array<R: AsyncRead + Unpin>(
+        array: ArrayFuture<R>,
+    ) -> IoResult<(Result<Self, ExtractError>, R)>;
+}
+
+macro_rules! from_array_tuple {
+    ($len:expr; $($ty:ident $var:ident $index:expr),*) => {
+        impl<$($ty: FromMsgPack),*> FromArray for ($($ty,)*) {
+            async fn from_array<R: AsyncRead + Unpin>(
+                array: ArrayFuture<R>,
+            ) -> IoResult<(Result<Self, ExtractError>, R)> {
+                if array.len() != $len {
+                    let err = ExtractError::Length {
+                        expected: $len,
+                        actual: array.len(),
+                    };
+                    return array.skip().await.map(|r| (Err(err), r));
+                }
+                $(
+                    let ($var, array) = $ty::from_msgpack(array.next().unwrap()).await?;
+                    let $var = match $var {
+                        Ok(val) => val,
+                        Err(error) => {
+                            let err = ExtractError::Element { index: $index, error };
+                            return array.skip().await.map(|r| (Err(err), r));
+                        }
+                    };
+                )*
+                Ok((Ok(($($var,)*)), array.next().unwrap_end()))
+            }
+        }
+    };
+}
+
+from_array_tuple!(0;);
+from_array_tuple!(1; A a 0);
+from_array_tuple!(2; A a 0, B b 1);
+from_array_tuple!(3; A a 0, B b 1, C c 2);
+from_array_tuple!(4; A a 0, B b 1, C c 2, D d 3);
+from_array_tuple!(5; A a 0, B b 1, C c 2, D d 3, E e 4);
+from_array_tuple!(6; A a 0, B b 1, C c 2, D d 3, E e 4, F f 5);
+from_array_tuple!(7; A a 0, B b 1, C c 2, D d 3, E e 4, F f 5, G g 6);
+from_array_tuple!(8; A a 0, B b 1, C c 2, D d 3, E e 4, F f 5, G g 6, H h 7);
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1022,4 +1481,133 @@ mod test {
             .unwrap();
         assert_eq!(out, val);
     }
+
+    #[test]
+    fn match_table() {
+        async fn match_test(buf: Cursor<Vec<u8>>, table: &[&str]) -> IoResult<Option<usize>> {
+            let s = MsgPackFuture::new(buf)
+                .decode()
+                .await?
+                .into_string()
+                .unwrap();
+            let (index, r) = s.match_table(table).await?;
+            assert_eq!(r.position(), r.get_ref().len() as u64);
+            Ok(index)
+        }
+
+        let long = "x".repeat(40);
+        let long_other = "x".repeat(39) + "y";
+        let table = [
+            "nvim_get_mode",
+            "nvim_input",
+            "nvim_input_mouse",
+            &long_other,
+            &long,
+        ];
+        let run = |s: &str| {
+            futures::executor::LocalPool::new()
+                .run_until(match_test(value_to_vec(&s.into()), &table))
+                .unwrap()
+        };
+        assert_eq!(run("nvim_input"), Some(1));
+        assert_eq!(run("nvim_input_mouse"), Some(2));
+        assert_eq!(run(&long), Some(4));
+        assert_eq!(run(&long_other), Some(3));
+        assert_eq!(run("nvim_inputs"), None);
+        assert_eq!(run(""), None);
+    }
+
+    #[test]
+    fn from_msgpack() {
+        use crate::encode::{MsgPackSink, ToMsgPack};
+
+        async fn roundtrip() -> IoResult<()> {
+            let w = MsgPackSink::new(Vec::new());
+            let w = vec![Some(1u8), None, Some(3)].to_msgpack(w).await?;
+            let w = "no".to_msgpack(MsgPackSink::new(w)).await?;
+            let w = vec![vec![1i64, -2], vec![]]
+                .to_msgpack(MsgPackSink::new(w))
+                .await?;
+            let w = vec![1u32, 300].to_msgpack(MsgPackSink::new(w)).await?;
+            let w = 2.5f32.to_msgpack(MsgPackSink::new(w)).await?;
+
+            let r = MsgPackFuture::new(Cursor::new(w));
+            let (val, r) = Vec::<Option<u8>>::from_msgpack(r).await?;
+            assert_eq!(val, Ok(vec![Some(1), None, Some(3)]));
+            // Mismatched types are skipped
+            let (val, r) = bool::from_msgpack(MsgPackFuture::new(r)).await?;
+            assert_eq!(val, Err(TypeError::new("bool")));
+            let (val, r) = Vec::<Vec<i64>>::from_msgpack(MsgPackFuture::new(r)).await?;
+            assert_eq!(val, Ok(vec![vec![1, -2], vec![]]));
+            // Out of range, and the rest of the array is skipped
+            let (val, r) = Vec::<u8>::from_msgpack(MsgPackFuture::new(r)).await?;
+            assert_eq!(val.unwrap_err().to_string(), "expected u8");
+            let (val, r) = f64::from_msgpack(MsgPackFuture::new(r)).await?;
+            assert_eq!(val, Ok(2.5));
+            assert_eq!(r.position(), r.get_ref().len() as u64);
+            Ok(())
+        }
+
+        futures::executor::LocalPool::new()
+            .run_until(roundtrip())
+            .unwrap();
+    }
+
+    #[test]
+    fn copy_to() {
+        let val = Value::Array(vec![
+            Value::Map(vec![("key".into(), Value::Binary(vec![0; 300]))]),
+            Value::Ext(3, vec![1, 2, 3]),
+            Value::Array(vec![(-5).into(), 70000.into(), 1.5.into(), Value::Nil]),
+            true.into(),
+        ]);
+        let mut input = value_to_vec(&val).into_inner();
+        // Negative ext types are reserved, and rmp refuses to write them
+        input.extend(&[0xd6, 0xff, 0, 0, 0, 1]);
+
+        async fn copy_twice(input: Vec<u8>) -> IoResult<(Vec<u8>, Cursor<Vec<u8>>)> {
+            let mut out = Vec::new();
+            let r = MsgPackFuture::new(Cursor::new(input))
+                .copy_to(&mut out)
+                .await?;
+            let r = MsgPackFuture::new(r).copy_to(&mut out).await?;
+            Ok((out, r))
+        }
+
+        let (out, r) = futures::executor::LocalPool::new()
+            .run_until(copy_twice(input.clone()))
+            .unwrap();
+        // Everything was already encoded as compactly as possible
+        assert_eq!(out, input);
+        assert_eq!(r.position(), input.len() as u64);
+    }
+
+    #[test]
+    fn skip_deep_nesting() {
+        // Arrays and maps of one element, nested far deeper than recursion
+        // could handle, followed by another message
+        let depth = 100_000;
+        let mut input = Vec::new();
+        for i in 0..depth {
+            if i % 2 == 0 {
+                input.push(0x91);
+            } else {
+                // The key is nil, and the value nests further
+                input.extend(&[0x81, 0xc0]);
+            }
+        }
+        input.push(0xc0);
+        input.push(0xc3);
+
+        async fn skip_then_bool(input: Vec<u8>) -> IoResult<bool> {
+            let r = MsgPackFuture::new(Cursor::new(input)).skip().await?;
+            let (val, _r) = MsgPackFuture::new(r).decode().await?.into_bool().unwrap();
+            Ok(val)
+        }
+
+        let val = futures::executor::LocalPool::new()
+            .run_until(skip_then_bool(input))
+            .unwrap();
+        assert!(val);
+    }
 }
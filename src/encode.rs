@@ -603,6 +603,109 @@ impl<W: AsyncWrite + Unpin> AsyncWrite for MsgPackSink<W> {
     }
 }
 
+/// Types that can be written as a single msgpack message
+#[allow(async_fn_in_trait)]
+pub trait ToMsgPack {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W>;
+}
+
+impl<T: ToMsgPack + ?Sized> ToMsgPack for &T {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        T::to_msgpack(self, sink).await
+    }
+}
+
+/// Unit is written as nil
+impl ToMsgPack for () {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_nil().await
+    }
+}
+
+impl ToMsgPack for bool {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_bool(*self).await
+    }
+}
+
+macro_rules! to_msgpack_int {
+    ($($ty:ty),*) => {
+        $(
+            impl ToMsgPack for $ty {
+                async fn to_msgpack<W: AsyncWrite + Unpin>(
+                    &self,
+                    sink: MsgPackSink<W>,
+                ) -> IoResult<W> {
+                    sink.write_int(*self).await
+                }
+            }
+        )*
+    };
+}
+
+to_msgpack_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl ToMsgPack for f32 {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_f32(*self).await
+    }
+}
+
+impl ToMsgPack for f64 {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_f64(*self).await
+    }
+}
+
+impl ToMsgPack for str {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_str(self).await
+    }
+}
+
+impl ToMsgPack for String {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_str(self).await
+    }
+}
+
+/// `None` is written as nil
+impl<T: ToMsgPack> ToMsgPack for Option<T> {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        match self {
+            Some(val) => val.to_msgpack(sink).await,
+            None => sink.write_nil().await,
+        }
+    }
+}
+
+/// Slices are written as arrays
+///
+/// # Panics
+///
+/// Panics if the length exceeds 2^32-1
+impl<T: ToMsgPack> ToMsgPack for [T] {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        let mut w = sink.write_array_len(self.len().try_into().unwrap()).await?;
+        for elem in self {
+            w = elem.to_msgpack(MsgPackSink::new(w)).await?;
+        }
+        Ok(w)
+    }
+}
+
+impl<T: ToMsgPack> ToMsgPack for Vec<T> {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        self[..].to_msgpack(sink).await
+    }
+}
+
+impl ToMsgPack for Value {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        sink.write_value(self).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
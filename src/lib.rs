@@ -4,6 +4,7 @@
 
 pub mod decode;
 pub mod encode;
+pub mod nvim;
 pub mod rpc;
 
 /// Used when iterating over collections, to return either the next item or
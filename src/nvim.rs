@@ -0,0 +1,248 @@
+//! Neovim's buffer, window and tabpage handles.
+//!
+//! Neovim sends handles as msgpack ext values whose payload is itself an
+//! encoded integer. The ext type of each kind of handle is listed in the
+//! `types` section of the `nvim_get_api_info` metadata.
+
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use rmpv::Value;
+
+use crate::decode::{mismatch, ExtFuture, FromMsgPack, MsgPackFuture, TypeError, ValueFuture};
+use crate::encode::MsgPackSink;
+
+/// Longest encoding of an integer, and so of a handle's payload
+const MAX_HANDLE_LEN: usize = 9;
+
+/// Ext type ids of each kind of handle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtTypes {
+    pub buffer: i8,
+    pub window: i8,
+    pub tabpage: i8,
+}
+
+/// The ids Neovim has always used, for when the metadata isn't available
+impl Default for ExtTypes {
+    fn default() -> Self {
+        ExtTypes {
+            buffer: 0,
+            window: 1,
+            tabpage: 2,
+        }
+    }
+}
+
+impl ExtTypes {
+    /// Read the ids from the result of `nvim_get_api_info`, or from just the
+    /// metadata map in it. Returns `None` if any of them is missing.
+    pub fn from_api_info(info: &Value) -> Option<Self> {
+        let metadata = match info {
+            Value::Array(a) => a.get(1)?,
+            info => info,
+        };
+        let types = map_get(metadata, "types")?;
+        let id = |name| {
+            let id = map_get(map_get(types, name)?, "id")?.as_i64()?;
+            std::convert::TryFrom::try_from(id).ok()
+        };
+        Some(ExtTypes {
+            buffer: id(Buffer::NAME)?,
+            window: id(Window::NAME)?,
+            tabpage: id(Tabpage::NAME)?,
+        })
+    }
+}
+
+fn map_get<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+}
+
+/// A kind of handle, encoded as an ext value holding the handle's integer
+#[allow(async_fn_in_trait)]
+pub trait Handle: Sized {
+    /// Name of the type in the API metadata
+    const NAME: &'static str;
+
+    fn ext_type(types: &ExtTypes) -> i8;
+
+    fn from_handle(handle: i64) -> Self;
+
+    fn handle(&self) -> i64;
+
+    /// Decode an ext value. An ext of another type, or with a payload that
+    /// isn't an integer, is reported as a `TypeError`. A payload too long to
+    /// be an integer fails with `ErrorKind::InvalidData` before it's read.
+    async fn from_ext<R: AsyncRead + Unpin>(
+        ext: ExtFuture<R>,
+        types: &ExtTypes,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        if ext.ext_type() != Self::ext_type(types) {
+            return ext
+                .skip()
+                .await
+                .map(|r| (Err(TypeError::new(Self::NAME)), r));
+        }
+        if ext.len() > MAX_HANDLE_LEN {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("{} handle of {} bytes", Self::NAME, ext.len()),
+            ));
+        }
+        let (_, payload, r) = ext.into_vec().await?;
+        // The payload has been read in full, so failing to decode it, even
+        // when it's cut short, just means it isn't a handle
+        let handle = match i64::from_msgpack(MsgPackFuture::new(&payload[..])).await {
+            Ok((Ok(handle), [])) => Ok(Self::from_handle(handle)),
+            _ => Err(TypeError::new(Self::NAME)),
+        };
+        Ok((handle, r))
+    }
+
+    /// Decode the rest of a message whose type has already been read
+    async fn from_value_future<R: AsyncRead + Unpin>(
+        value: ValueFuture<R>,
+        types: &ExtTypes,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        match value {
+            ValueFuture::Ext(ext) => Self::from_ext(ext, types).await,
+            value => mismatch(value, Self::NAME).await,
+        }
+    }
+
+    async fn from_msgpack<R: AsyncRead + Unpin>(
+        msg: MsgPackFuture<R>,
+        types: &ExtTypes,
+    ) -> IoResult<(Result<Self, TypeError>, R)> {
+        Self::from_value_future(msg.decode().await?, types).await
+    }
+
+    async fn to_msgpack<W: AsyncWrite + Unpin>(
+        &self,
+        sink: MsgPackSink<W>,
+        types: &ExtTypes,
+    ) -> IoResult<W> {
+        let payload = MsgPackSink::new(Vec::new())
+            .write_int(self.handle())
+            .await?;
+        let mut w = sink
+            .write_ext_meta(payload.len() as u32, Self::ext_type(types))
+            .await?;
+        w.write_all(&payload).await?;
+        Ok(w)
+    }
+}
+
+macro_rules! handle {
+    ($(#[$doc:meta])* $name:ident, $field:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub struct $name(pub i64);
+
+        impl Handle for $name {
+            const NAME: &'static str = stringify!($name);
+
+            fn ext_type(types: &ExtTypes) -> i8 {
+                types.$field
+            }
+
+            fn from_handle(handle: i64) -> Self {
+                $name(handle)
+            }
+
+            fn handle(&self) -> i64 {
+                self.0
+            }
+        }
+    };
+}
+
+handle!(
+    /// Handle of a Neovim buffer
+    Buffer,
+    buffer
+);
+handle!(
+    /// Handle of a Neovim window
+    Window,
+    window
+);
+handle!(
+    /// Handle of a Neovim tabpage
+    Tabpage,
+    tabpage
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn api_info() -> Value {
+        let ty = |id: i64, prefix: &str| {
+            Value::Map(vec![
+                ("id".into(), id.into()),
+                ("prefix".into(), prefix.into()),
+            ])
+        };
+        let metadata = Value::Map(vec![(
+            "types".into(),
+            Value::Map(vec![
+                ("Buffer".into(), ty(5, "nvim_buf_")),
+                ("Window".into(), ty(6, "nvim_win_")),
+                ("Tabpage".into(), ty(7, "nvim_tabpage_")),
+            ]),
+        )]);
+        Value::Array(vec![1.into(), metadata])
+    }
+
+    #[test]
+    fn api_types() {
+        let types = ExtTypes::from_api_info(&api_info()).unwrap();
+        assert_eq!(
+            types,
+            ExtTypes {
+                buffer: 5,
+                window: 6,
+                tabpage: 7
+            }
+        );
+        assert_eq!(ExtTypes::from_api_info(&Value::Map(vec![])), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let types = ExtTypes::from_api_info(&api_info()).unwrap();
+        let buf = block_on(Window(1000).to_msgpack(MsgPackSink::new(Vec::new()), &types)).unwrap();
+        // ext 8 of length 3 and type 6, holding the uint16 1000
+        assert_eq!(buf, [0xc7, 3, 6, 0xcd, 0x03, 0xe8]);
+
+        let (window, rest) =
+            block_on(Window::from_msgpack(MsgPackFuture::new(&buf[..]), &types)).unwrap();
+        assert_eq!(window, Ok(Window(1000)));
+        assert!(rest.is_empty());
+
+        // A window isn't a buffer, even with the same handle
+        let (buffer, rest) =
+            block_on(Buffer::from_msgpack(MsgPackFuture::new(&buf[..]), &types)).unwrap();
+        assert_eq!(buffer, Err(TypeError::new("Buffer")));
+        assert!(rest.is_empty());
+
+        // fixext 1 of type 6, holding the start of a uint16
+        let buf = [0xd4, 6, 0xcd];
+        let (window, rest) =
+            block_on(Window::from_msgpack(MsgPackFuture::new(&buf[..]), &types)).unwrap();
+        assert_eq!(window, Err(TypeError::new("Window")));
+        assert!(rest.is_empty());
+
+        // ext 32 claiming a 4GB payload
+        let buf = [0xc9, 0xff, 0xff, 0xff, 0xff, 6];
+        let err = block_on(Window::from_msgpack(MsgPackFuture::new(&buf[..]), &types)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
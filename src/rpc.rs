@@ -1 +1,18 @@
+pub mod accept;
+pub mod client;
 pub mod decode;
+pub mod encode;
+pub mod error;
+pub mod limit;
+pub mod middleware;
+pub mod observe;
+pub mod proxy;
+pub mod record;
+pub mod server;
+pub mod service;
+pub mod session;
+pub mod shared;
+pub mod subscription;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transport;
@@ -0,0 +1,193 @@
+//! Serving RPC sessions on every connection accepted by a listener.
+
+use futures::io::{Error as IoError, ReadHalf, Result as IoResult, WriteHalf};
+use futures::prelude::*;
+use futures::task::{LocalSpawn, LocalSpawnExt};
+
+use crate::rpc::decode::RpcStream;
+use crate::rpc::limit::{Limits, Semaphore};
+use crate::rpc::server::RpcServer;
+use crate::rpc::session::RpcSession;
+
+/// Failures to accept in a row before `Acceptor::run()` gives up. There are
+/// no timers to back off with, and a listener that's failing this often,
+/// e.g. because the process is out of file descriptors, would otherwise be
+/// polled in a busy loop.
+const MAX_ACCEPT_ERRORS: usize = 16;
+
+/// Server for the connections of type `T`
+pub type ConnectionServer<T> = RpcServer<RpcStream<ReadHalf<T>>, WriteHalf<T>>;
+
+/// Runs an `RpcSession` for each incoming connection, on any executor that
+/// can spawn local futures. Sessions are single-threaded, so they can't be
+/// spawned onto a `Spawn` executor that might move them between threads.
+pub struct Acceptor<F> {
+    factory: F,
+    max_connections: Option<usize>,
+    limits: Limits,
+}
+
+impl<T, F> Acceptor<F>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+    F: FnMut() -> ConnectionServer<T>,
+{
+    /// Serve each connection with a server made by `factory`, which creates
+    /// whatever state the connection's handlers share
+    pub fn new(factory: F) -> Self {
+        Acceptor {
+            factory,
+            max_connections: None,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Stop accepting connections while `max` are open
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Limits applied to each session
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Accept connections from `incoming` and spawn a session for each onto
+    /// `spawner`, until `incoming` ends. Sessions still running then are
+    /// left to finish on the spawner.
+    ///
+    /// Failing to accept a connection, or a session failing, is logged
+    /// without affecting other connections. Failing to spawn is returned, as
+    /// is the last error once accepting has failed `MAX_ACCEPT_ERRORS` times
+    /// in a row.
+    pub async fn run<I, S>(mut self, incoming: I, mut spawner: S) -> IoResult<()>
+    where
+        I: Stream<Item = IoResult<T>> + Unpin,
+        S: LocalSpawn,
+    {
+        let connections = self.max_connections.map(Semaphore::new);
+        let mut incoming = incoming;
+        let mut errors = 0;
+        loop {
+            // Wait for a free slot before accepting, leaving connections
+            // queued in the listener
+            let permit = match &connections {
+                Some(connections) => Some(connections.acquire().await),
+                None => None,
+            };
+            let io = match incoming.next().await {
+                Some(Ok(io)) => io,
+                Some(Err(e)) => {
+                    errors += 1;
+                    if errors == MAX_ACCEPT_ERRORS {
+                        return Err(e);
+                    }
+                    log::warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+                None => return Ok(()),
+            };
+            errors = 0;
+            let (reader, writer) = io.split();
+            let session = RpcSession::with_limits(reader, writer, (self.factory)(), self.limits);
+            spawner
+                .spawn_local(async move {
+                    if let Err(e) = session.run().await {
+                        log::warn!("session failed: {}", e);
+                    }
+                    drop(permit);
+                })
+                .map_err(|e| IoError::other(format!("failed to spawn session: {:?}", e)))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::transport::{duplex, DuplexStream};
+    use futures::channel::mpsc;
+    use futures::executor::LocalPool;
+    use rmpv::Value;
+
+    #[test]
+    fn max_connections() {
+        let mut pool = LocalPool::new();
+        let mut spawner = pool.spawner();
+
+        // Each connection's server knows which connection it is
+        let mut count = 0;
+        let acceptor = Acceptor::new(move || {
+            let mut server = RpcServer::new();
+            let id = count;
+            server.add_method("id", move |_| future::ready(Ok(id.into())));
+            count += 1;
+            server
+        })
+        .with_max_connections(1);
+        let (listener, incoming) = mpsc::unbounded::<IoResult<DuplexStream>>();
+        spawner
+            .spawn_local(acceptor.run(incoming, pool.spawner()).map(Result::unwrap))
+            .unwrap();
+
+        // Connect, and call `id` on the new connection
+        let (results, mut served) = mpsc::unbounded();
+        let mut connect = |listener: &mpsc::UnboundedSender<_>| {
+            let (client, server) = duplex(1024);
+            listener.unbounded_send(Ok(server)).unwrap();
+            let session = RpcSession::from_duplex(client, RpcServer::new());
+            let client = session.client();
+            let results = results.clone();
+            let shutdown = session.shutdown_handle();
+            spawner
+                .spawn_local(session.run().map(Result::unwrap))
+                .unwrap();
+            spawner
+                .spawn_local(async move {
+                    let result = client.call_value("id", &[]).await.unwrap().unwrap();
+                    results.unbounded_send(result).unwrap();
+                })
+                .unwrap();
+            shutdown
+        };
+
+        let first = connect(&listener);
+        let _second = connect(&listener);
+        assert_eq!(pool.run_until(served.next()), Some(Value::from(0)));
+        pool.run_until_stalled();
+        assert!(served.try_next().is_err());
+
+        // Closing the first connection lets the second be accepted
+        first.shutdown();
+        assert_eq!(pool.run_until(served.next()), Some(Value::from(1)));
+    }
+
+    #[test]
+    fn accept_errors() {
+        let errors = |n| {
+            let error = || Err::<DuplexStream, _>(IoError::other("too many open files"));
+            stream::iter((0..n).map(move |_| error()))
+        };
+        fn run<I>(pool: &mut LocalPool, incoming: I) -> IoResult<()>
+        where
+            I: Stream<Item = IoResult<DuplexStream>> + Unpin,
+        {
+            let acceptor = Acceptor::new(RpcServer::new);
+            let spawner = pool.spawner();
+            pool.run_until(acceptor.run(incoming, spawner))
+        }
+        let mut pool = LocalPool::new();
+
+        // A connection in between resets the count
+        let (_, server) = duplex(1024);
+        let incoming = errors(MAX_ACCEPT_ERRORS - 1)
+            .chain(stream::once(future::ready(Ok(server))))
+            .chain(errors(MAX_ACCEPT_ERRORS - 1));
+        run(&mut pool, incoming).unwrap();
+
+        let err = run(&mut pool, errors(MAX_ACCEPT_ERRORS)).unwrap_err();
+        assert_eq!(err.to_string(), "too many open files");
+    }
+}
@@ -0,0 +1,726 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::channel::oneshot;
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use rmpv::Value;
+
+use crate::decode::{FromMsgPack, MsgPackFuture, ValueFuture};
+use crate::rpc::decode::RpcResponseFuture;
+use crate::rpc::encode::RpcParamsSink;
+use crate::rpc::error::{ApplicationError, MsgIdsExhausted, RpcError};
+use crate::rpc::limit::{LimitedBuf, Permit, Semaphore};
+use crate::rpc::shared::{SharedWriter, SharedWriterGuard};
+use crate::MsgPackOption;
+
+/// Decoded response to a call: the result value, or the error value if it
+/// wasn't nil
+pub type RpcResult = Result<Value, Value>;
+
+/// Response to a call made with `RpcClient::call_typed()`. Errors in the
+/// conventional encoding, and responses of the wrong type, are an `RpcError`.
+pub type TypedResult<T, E> = Result<Result<T, E>, RpcError>;
+
+/// What to do with a response whose msgid doesn't match an outstanding call
+pub enum UnknownResponse {
+    /// Skip the response and fail `handle_response()` with
+    /// `ErrorKind::InvalidData`
+    Error,
+    /// Log a warning and skip the response
+    Skip,
+    /// Pass the msgid to a callback and skip the response
+    Callback(Box<dyn FnMut(u32)>),
+}
+
+type DecodeFuture<'a> = Pin<Box<dyn Future<Output = IoResult<()>> + 'a>>;
+
+/// Reads the error and result fields of a response, from a reader borrowed
+/// from the connection, and passes them on to a typed call
+trait Decoder {
+    fn is_canceled(&self) -> bool;
+
+    fn decode<'a>(
+        self: Box<Self>,
+        reader: &'a mut (dyn AsyncRead + Unpin + 'a),
+    ) -> DecodeFuture<'a>;
+}
+
+impl<T, E> Decoder for oneshot::Sender<TypedResult<T, E>>
+where
+    T: FromMsgPack + 'static,
+    E: FromMsgPack + 'static,
+{
+    fn is_canceled(&self) -> bool {
+        oneshot::Sender::is_canceled(self)
+    }
+
+    fn decode<'a>(
+        self: Box<Self>,
+        reader: &'a mut (dyn AsyncRead + Unpin + 'a),
+    ) -> DecodeFuture<'a> {
+        async move {
+            let (result, _) = decode_typed(reader).await?;
+            // The call may have been dropped, leaving nobody to notify
+            let _ = self.send(result);
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// Most bytes of an error array buffered to check whether it's in the
+/// conventional encoding
+const MAX_ERROR_LEN: usize = 64 * 1024;
+
+/// Whether an error value might be in the conventional `[code, message]` or
+/// `[code, message, data]` encoding
+fn maybe_conventional<R: AsyncRead + Unpin>(error: &ValueFuture<R>) -> bool {
+    match error {
+        ValueFuture::Array(a) => a.len() == 2 || a.len() == 3,
+        _ => false,
+    }
+}
+
+/// Decode the error and result fields of a response as `E` and `T`
+async fn decode_typed<T, E, R>(reader: R) -> IoResult<(TypedResult<T, E>, R)>
+where
+    T: FromMsgPack,
+    E: FromMsgPack,
+    R: AsyncRead + Unpin,
+{
+    let (result, r) = match MsgPackFuture::new(reader).decode().await? {
+        ValueFuture::Nil(r) => {
+            let (result, r) = T::from_msgpack(MsgPackFuture::new(r)).await?;
+            return Ok((result.map(Ok).map_err(RpcError::Decode), r));
+        }
+        // Conventional errors can only be told apart from an `E` that's an
+        // array of the same length by reading the whole thing, so they're
+        // buffered, up to `MAX_ERROR_LEN`. Other errors are decoded as
+        // they're read.
+        array if maybe_conventional(&array) => {
+            let mut buf = LimitedBuf::new(MAX_ERROR_LEN, "error response");
+            let r = array.copy_to(&mut buf).await?;
+            let buf = buf.into_inner();
+            let value = rmpv::decode::read_value(&mut &buf[..])
+                .map_err(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))?;
+            let result = match ApplicationError::from_value(&value) {
+                Some(e) => Err(RpcError::Application(e)),
+                None => {
+                    let (e, _) = E::from_msgpack(MsgPackFuture::new(&buf[..])).await?;
+                    e.map(Err).map_err(RpcError::Decode)
+                }
+            };
+            (result, r)
+        }
+        error => {
+            let (e, r) = E::from_value_future(error).await?;
+            (e.map(Err).map_err(RpcError::Decode), r)
+        }
+    };
+    // The result of a failed call is ignored
+    Ok((result, MsgPackFuture::new(r).skip().await?))
+}
+
+/// Where the response to an outstanding call goes
+enum Pending {
+    /// Made with `call()`, so the response is decoded into `Value`s
+    Value(oneshot::Sender<RpcResult>),
+    /// Made with `call_typed()`
+    Typed(Box<dyn Decoder>),
+}
+
+impl Pending {
+    fn is_canceled(&self) -> bool {
+        match self {
+            Pending::Value(tx) => tx.is_canceled(),
+            Pending::Typed(decoder) => decoder.is_canceled(),
+        }
+    }
+}
+
+/// How many dropped calls are remembered. A peer that never answers them
+/// would otherwise grow `Calls::cancelled` forever, so past this many the
+/// oldest is forgotten, and a late response to it is treated as unknown.
+const MAX_CANCELLED: usize = 4096;
+
+/// Outstanding calls, keyed by msgid
+struct Calls {
+    next_id: u32,
+    pending: HashMap<u32, Pending>,
+    /// Calls dropped before their response arrived. Their msgids aren't
+    /// reused until the response turns up and is skipped, or they're evicted.
+    cancelled: HashSet<u32>,
+    /// Msgids in the order they were cancelled, oldest first. Ids whose
+    /// response has since arrived are left in place until they reach the
+    /// front, so this never holds more than `MAX_CANCELLED`.
+    cancel_order: VecDeque<u32>,
+    /// Set by `RpcClient::close()`
+    closed: bool,
+}
+
+impl Calls {
+    /// Allocate the next msgid that isn't already in use, wrapping around at
+    /// `u32::MAX`
+    fn alloc_id(&mut self) -> Result<u32, MsgIdsExhausted> {
+        if self.pending.len() + self.cancelled.len() > u32::MAX as usize {
+            return Err(MsgIdsExhausted);
+        }
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.pending.contains_key(&id) && !self.cancelled.contains(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Remember that the call with msgid `id` was dropped, forgetting the
+    /// oldest cancellation if there are too many
+    fn cancel(&mut self, id: u32) {
+        self.cancelled.insert(id);
+        self.cancel_order.push_back(id);
+        if self.cancel_order.len() > MAX_CANCELLED {
+            if let Some(old) = self.cancel_order.pop_front() {
+                self.cancelled.remove(&old);
+            }
+        }
+    }
+}
+
+/// Client side of an RPC connection. Allocates msgids for outgoing calls and
+/// routes responses from the read side back to the matching call.
+///
+/// Calls are written through a `SharedWriter`, so any number of them can be
+/// made concurrently. Responses must be passed to `handle_response()` by
+/// whatever is reading the `RpcStream`.
+pub struct RpcClient<W> {
+    writer: SharedWriter<W>,
+    calls: Rc<RefCell<Calls>>,
+    unknown: Rc<RefCell<UnknownResponse>>,
+    max_pending: Option<Semaphore>,
+}
+
+impl<W> Clone for RpcClient<W> {
+    fn clone(&self) -> Self {
+        RpcClient {
+            writer: self.writer.clone(),
+            calls: self.calls.clone(),
+            unknown: self.unknown.clone(),
+            max_pending: self.max_pending.clone(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> RpcClient<W> {
+    /// Create a client that fails on responses with an unknown msgid
+    pub fn new(writer: SharedWriter<W>) -> Self {
+        Self::with_unknown_response(writer, UnknownResponse::Error)
+    }
+
+    pub fn with_unknown_response(writer: SharedWriter<W>, unknown: UnknownResponse) -> Self {
+        RpcClient {
+            writer,
+            calls: Rc::new(RefCell::new(Calls {
+                next_id: 0,
+                pending: HashMap::new(),
+                cancelled: HashSet::new(),
+                cancel_order: VecDeque::new(),
+                closed: false,
+            })),
+            unknown: Rc::new(RefCell::new(unknown)),
+            max_pending: None,
+        }
+    }
+
+    /// Limit the number of calls awaiting a response. Once there are `max`,
+    /// `call()` waits until one of them completes or is dropped.
+    pub fn with_max_pending(mut self, max: usize) -> Self {
+        self.max_pending = Some(Semaphore::new(max));
+        self
+    }
+
+    pub fn writer(&self) -> &SharedWriter<W> {
+        &self.writer
+    }
+
+    /// Number of calls awaiting a response
+    pub fn pending(&self) -> usize {
+        self.calls.borrow().pending.len()
+    }
+
+    /// Fail every pending call, and any made from now on, with
+    /// `ErrorKind::NotConnected`. Called once no more responses can arrive.
+    pub fn close(&self) {
+        let mut calls = self.calls.borrow_mut();
+        calls.closed = true;
+        // Dropping the senders wakes the calls
+        calls.pending.clear();
+        calls.cancelled.clear();
+        calls.cancel_order.clear();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.calls.borrow().closed
+    }
+
+    /// Start a call, returning a sink for exactly `num_params` params and a
+    /// future that resolves when the response arrives.
+    ///
+    /// Fails with a `MsgIdsExhausted` error if every msgid is taken by a
+    /// pending or cancelled call. Use `with_max_pending()` to wait instead.
+    pub async fn call(
+        &self,
+        method: &str,
+        num_params: u32,
+    ) -> IoResult<(RpcParamsSink<SharedWriterGuard<W>>, RpcCall)> {
+        let (tx, rx) = oneshot::channel();
+        self.start_call(method, num_params, Pending::Value(tx), rx)
+            .await
+    }
+
+    /// Like `call()`, but the response is decoded as `T` or `E` straight
+    /// from the connection by `handle_response()`
+    pub async fn call_typed<T, E>(
+        &self,
+        method: &str,
+        num_params: u32,
+    ) -> IoResult<(
+        RpcParamsSink<SharedWriterGuard<W>>,
+        RpcCall<TypedResult<T, E>>,
+    )>
+    where
+        T: FromMsgPack + 'static,
+        E: FromMsgPack + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.start_call(method, num_params, Pending::Typed(Box::new(tx)), rx)
+            .await
+    }
+
+    async fn start_call<T>(
+        &self,
+        method: &str,
+        num_params: u32,
+        pending: Pending,
+        rx: oneshot::Receiver<T>,
+    ) -> IoResult<(RpcParamsSink<SharedWriterGuard<W>>, RpcCall<T>)> {
+        let permit = match &self.max_pending {
+            Some(limit) => Some(limit.acquire().await),
+            None => None,
+        };
+        let id = {
+            let mut calls = self.calls.borrow_mut();
+            if calls.closed {
+                return Err(connection_closed());
+            }
+            let id = calls.alloc_id()?;
+            calls.pending.insert(id, pending);
+            id
+        };
+        match self.writer.request(id, method, num_params).await {
+            Ok(params) => Ok((
+                params,
+                RpcCall {
+                    id,
+                    rx,
+                    calls: self.calls.clone(),
+                    _permit: permit,
+                },
+            )),
+            Err(e) => {
+                self.calls.borrow_mut().pending.remove(&id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Make a call with params from dynamic `Value`s and wait for the
+    /// response
+    pub async fn call_value(&self, method: &str, params: &[Value]) -> IoResult<RpcResult> {
+        let len = params.len() as u32;
+        let (mut sink, call) = self.call(method, len).await?;
+        for param in params {
+            sink = match sink.next() {
+                MsgPackOption::Some(m) => m.write_value(param).await?,
+                MsgPackOption::End(_) => unreachable!(),
+            };
+        }
+        sink.next().unwrap_end().release().await?;
+        call.await
+    }
+
+    /// Route a response to the call waiting for it, returning the reader
+    /// positioned after the response
+    pub async fn handle_response<R>(&self, resp: RpcResponseFuture<R>) -> IoResult<R>
+    where
+        R: AsyncRead + Unpin + 'static,
+    {
+        let id = resp.id();
+        let pending = self.calls.borrow_mut().pending.remove(&id);
+        match pending {
+            Some(Pending::Typed(decoder)) => {
+                let mut reader = resp.into_fields()?;
+                decoder.decode(&mut reader).await?;
+                Ok(reader)
+            }
+            Some(Pending::Value(tx)) => {
+                let (error, e) = resp.error().await?.into_value().await?;
+                let (result, r) = e.result().await?.into_value().await?;
+                let reader = r.finish().await?;
+                // The call may have been dropped, leaving nobody to notify
+                let _ = tx.send(if error.is_nil() {
+                    Ok(result)
+                } else {
+                    Err(error)
+                });
+                Ok(reader)
+            }
+            None => {
+                let reader = resp.skip().await?;
+                if self.calls.borrow_mut().cancelled.remove(&id) {
+                    // Late response to a call that was dropped
+                    return Ok(reader);
+                }
+                match &mut *self.unknown.borrow_mut() {
+                    UnknownResponse::Error => {
+                        return Err(IoError::new(
+                            ErrorKind::InvalidData,
+                            format!("response to unknown msgid {}", id),
+                        ))
+                    }
+                    UnknownResponse::Skip => {
+                        log::warn!("skipping response to unknown msgid {}", id)
+                    }
+                    UnknownResponse::Callback(f) => f(id),
+                }
+                Ok(reader)
+            }
+        }
+    }
+}
+
+/// Future for the response to a call made with `RpcClient`. Dropping it
+/// before the response arrives cancels the call, and the response is skipped
+/// when it does arrive.
+pub struct RpcCall<T = RpcResult> {
+    id: u32,
+    rx: oneshot::Receiver<T>,
+    calls: Rc<RefCell<Calls>>,
+    /// Counts toward `RpcClient::with_max_pending()` until dropped
+    _permit: Option<Permit>,
+}
+
+impl RpcCall {
+    /// Wait for the response, folding transport, protocol and error
+    /// responses into an `RpcError`
+    pub async fn result(self) -> Result<Value, RpcError> {
+        RpcError::flatten(self.await)
+    }
+}
+
+impl<T> RpcCall<T> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Wait for the response until `timer` completes, then give up with
+    /// `ErrorKind::TimedOut` and cancel the call. Any future can serve as the
+    /// timer, so this works with whichever executor's timers are at hand.
+    pub async fn deadline(self, timer: impl Future<Output = ()>) -> IoResult<T> {
+        futures::pin_mut!(timer);
+        match future::select(self, timer).await {
+            future::Either::Left((result, _)) => result,
+            future::Either::Right(((), _)) => {
+                Err(IoError::new(ErrorKind::TimedOut, "call deadline expired"))
+            }
+        }
+    }
+}
+
+impl<T> Drop for RpcCall<T> {
+    fn drop(&mut self) {
+        self.rx.close();
+        let mut calls = self.calls.borrow_mut();
+        // The msgid may have been answered and already reused by another call,
+        // in which case the pending sender isn't ours
+        let ours = calls
+            .pending
+            .get(&self.id)
+            .is_some_and(Pending::is_canceled);
+        if ours {
+            calls.pending.remove(&self.id);
+            calls.cancel(self.id);
+        }
+    }
+}
+
+impl<T> Future for RpcCall<T> {
+    type Output = IoResult<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let calls = self.calls.clone();
+        Pin::new(&mut self.rx).poll(cx).map_err(|_| {
+            if calls.borrow().closed {
+                connection_closed()
+            } else {
+                IoError::new(
+                    ErrorKind::ConnectionAborted,
+                    "client dropped before the response arrived",
+                )
+            }
+        })
+    }
+}
+
+fn connection_closed() -> IoError {
+    IoError::new(ErrorKind::NotConnected, "connection closed")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::decode::{RpcMessage, RpcStream};
+    use std::io::Cursor;
+
+    fn responses(values: &[Value]) -> RpcStream<Cursor<Vec<u8>>> {
+        let mut buf = Vec::new();
+        for v in values {
+            rmpv::encode::write_value(&mut buf, v).unwrap();
+        }
+        RpcStream::new(Cursor::new(buf))
+    }
+
+    async fn read_responses<W: AsyncWrite + Unpin>(
+        client: RpcClient<W>,
+        mut stream: RpcStream<Cursor<Vec<u8>>>,
+        count: usize,
+    ) -> IoResult<()> {
+        for _ in 0..count {
+            stream = match stream.next().await? {
+                RpcMessage::Response(resp) => client.handle_response(resp).await?,
+                _ => panic!("Wrong message type"),
+            };
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn call_and_response() {
+        let client = RpcClient::new(SharedWriter::new(Vec::new()));
+        let stream = responses(&[
+            Value::Array(vec![1.into(), 1.into(), "bad".into(), Value::Nil]),
+            Value::Array(vec![1.into(), 0.into(), Value::Nil, 3.into()]),
+        ]);
+
+        let params = [1.into(), 2.into()];
+        let calls = future::join(
+            client.call_value("add", &params),
+            client.call_value("sub", &[]),
+        );
+        let (calls, read) = futures::executor::LocalPool::new().run_until(future::join(
+            calls,
+            read_responses(client.clone(), stream, 2),
+        ));
+        read.unwrap();
+        assert_eq!(calls.0.unwrap(), Ok(3.into()));
+        assert_eq!(calls.1.unwrap(), Err("bad".into()));
+        assert_eq!(client.pending(), 0);
+    }
+
+    #[test]
+    fn typed_errors() {
+        fn decode(error: Value) -> IoResult<TypedResult<i64, Vec<i64>>> {
+            let mut buf = Vec::new();
+            rmpv::encode::write_value(&mut buf, &error).unwrap();
+            rmpv::encode::write_value(&mut buf, &Value::Nil).unwrap();
+            let decode = decode_typed(&buf[..]);
+            let (result, rest) = futures::executor::block_on(decode)?;
+            assert!(rest.is_empty());
+            Ok(result)
+        }
+
+        let conventional = Value::Array(vec![1.into(), "bad".into()]);
+        match decode(conventional).unwrap() {
+            Err(RpcError::Application(e)) => assert_eq!(e.message, "bad"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        let codes = Value::Array(vec![1.into(), 2.into()]);
+        assert_eq!(decode(codes).unwrap().unwrap(), Err(vec![1, 2]));
+        let long = Value::Array((0..MAX_ERROR_LEN as i64).map(Value::from).collect());
+        assert_eq!(
+            decode(long).unwrap().unwrap(),
+            Err((0..MAX_ERROR_LEN as i64).collect())
+        );
+
+        let data = Value::Binary(vec![0; MAX_ERROR_LEN]);
+        let huge = Value::Array(vec![1.into(), "bad".into(), data]);
+        let e = decode(huge).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_response() {
+        let resp = Value::Array(vec![1.into(), 9.into(), Value::Nil, Value::Nil]);
+        let mut pool = futures::executor::LocalPool::new();
+
+        let client = RpcClient::new(SharedWriter::new(Vec::new()));
+        let err = pool
+            .run_until(read_responses(
+                client,
+                responses(std::slice::from_ref(&resp)),
+                1,
+            ))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let client =
+            RpcClient::with_unknown_response(SharedWriter::new(Vec::new()), UnknownResponse::Skip);
+        pool.run_until(read_responses(
+            client,
+            responses(std::slice::from_ref(&resp)),
+            1,
+        ))
+        .unwrap();
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_cb = seen.clone();
+        let client = RpcClient::with_unknown_response(
+            SharedWriter::new(Vec::new()),
+            UnknownResponse::Callback(Box::new(move |id| seen_cb.borrow_mut().push(id))),
+        );
+        pool.run_until(read_responses(client, responses(&[resp.clone(), resp]), 2))
+            .unwrap();
+        assert_eq!(*seen.borrow(), vec![9, 9]);
+    }
+
+    #[test]
+    fn msgid_wraparound() {
+        let mut calls = Calls {
+            next_id: u32::MAX - 1,
+            pending: HashMap::new(),
+            cancelled: HashSet::new(),
+            cancel_order: VecDeque::new(),
+            closed: false,
+        };
+        calls
+            .pending
+            .insert(u32::MAX, Pending::Value(oneshot::channel().0));
+        calls
+            .pending
+            .insert(0, Pending::Value(oneshot::channel().0));
+        calls.cancelled.insert(1);
+        assert_eq!(calls.alloc_id().unwrap(), u32::MAX - 1);
+        assert_eq!(calls.alloc_id().unwrap(), 2);
+    }
+
+    #[test]
+    fn cancel() {
+        async fn start_call(client: &RpcClient<Vec<u8>>) -> IoResult<RpcCall> {
+            let (params, call) = client.call("hang", 0).await?;
+            params.next().unwrap_end().release().await?;
+            Ok(call)
+        }
+
+        let client = RpcClient::new(SharedWriter::new(Vec::new()));
+        let mut pool = futures::executor::LocalPool::new();
+        let dropped = pool.run_until(start_call(&client)).unwrap();
+        let timed_out = pool.run_until(start_call(&client)).unwrap();
+        assert_eq!(client.pending(), 2);
+
+        drop(dropped);
+        let err = pool
+            .run_until(timed_out.deadline(future::ready(())))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(client.pending(), 0);
+
+        // Late responses are skipped rather than treated as unknown
+        let stream = responses(&[
+            Value::Array(vec![1.into(), 1.into(), Value::Nil, Value::Nil]),
+            Value::Array(vec![1.into(), 0.into(), Value::Nil, Value::Nil]),
+        ]);
+        pool.run_until(read_responses(client.clone(), stream, 2))
+            .unwrap();
+        let call = pool.run_until(start_call(&client)).unwrap();
+        assert_eq!(call.id(), 2);
+    }
+
+    #[test]
+    fn cancel_silent_peer() {
+        let client = RpcClient::new(SharedWriter::new(Vec::new()));
+        let mut pool = futures::executor::LocalPool::new();
+        let calls = MAX_CANCELLED as u32 * 2;
+        for _ in 0..calls {
+            let (params, call) = pool.run_until(client.call("hang", 0)).unwrap();
+            pool.run_until(params.next().unwrap_end().release())
+                .unwrap();
+            let err = pool
+                .run_until(call.deadline(future::ready(())))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+        }
+        assert_eq!(client.pending(), 0);
+        {
+            let state = client.calls.borrow();
+            assert_eq!(state.cancelled.len(), MAX_CANCELLED);
+            assert_eq!(state.cancel_order.len(), MAX_CANCELLED);
+            // Only the most recent cancellations are remembered
+            assert!(!state
+                .cancelled
+                .contains(&(calls - MAX_CANCELLED as u32 - 1)));
+            assert!(state.cancelled.contains(&(calls - MAX_CANCELLED as u32)));
+        }
+
+        // A late response to a remembered call is still skipped
+        let stream = responses(&[Value::Array(vec![
+            1.into(),
+            (calls - 1).into(),
+            Value::Nil,
+            Value::Nil,
+        ])]);
+        pool.run_until(read_responses(client.clone(), stream, 1))
+            .unwrap();
+        assert_eq!(client.calls.borrow().cancelled.len(), MAX_CANCELLED - 1);
+    }
+
+    #[test]
+    fn max_pending() {
+        use futures::task::noop_waker_ref;
+        use std::task::Context;
+
+        let client = RpcClient::new(SharedWriter::new(Vec::new())).with_max_pending(1);
+        let mut pool = futures::executor::LocalPool::new();
+        let (params, first) = pool.run_until(client.call("first", 0)).unwrap();
+        pool.run_until(params.next().unwrap_end().release())
+            .unwrap();
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut second = client.call("second", 0).boxed_local();
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        drop(first);
+        match second.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok((_, call))) => assert_eq!(call.id(), 1),
+            _ => panic!("call not started"),
+        }
+    }
+
+    #[test]
+    fn close() {
+        let client = RpcClient::new(SharedWriter::new(Vec::new()));
+        let mut pool = futures::executor::LocalPool::new();
+        let (_, call) = pool.run_until(client.call("hang", 0)).unwrap();
+        client.close();
+        assert!(client.is_closed());
+        assert_eq!(client.pending(), 0);
+        let err = pool.run_until(call).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        let err = pool.run_until(client.call_value("late", &[])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+    }
+}
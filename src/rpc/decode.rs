@@ -1,33 +1,71 @@
+use std::cell::RefCell;
 use std::convert::TryFrom;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
-use futures::io::Error as IoError;
 use futures::io::ErrorKind;
 use futures::io::Result as IoResult;
 use futures::prelude::*;
+use rmpv::Value;
 
 use crate::decode::{ArrayFuture, MsgPackFuture, StringFuture, ValueFuture};
+use crate::rpc::error::{ProtocolError, RpcError};
+use crate::rpc::observe::{Direction, MessageType, Recorder, Trace};
+use crate::rpc::shared::{RpcResponder, SharedWriter};
 
 pub enum RpcMessage<R> {
     Request(RpcRequestFuture<R>),
     Response(RpcResponseFuture<R>),
-    Notify,
+    Notify(RpcNotifyFuture<R>),
 }
 
+type SharedRecorder = Rc<RefCell<Recorder>>;
+
 impl<R> RpcMessage<R> {
-    fn request(array: ArrayFuture<R>, id: u32) -> Self {
-        RpcMessage::Request(RpcRequestFuture { array, id })
+    fn request(array: ArrayFuture<R>, id: u32, recorder: Option<SharedRecorder>) -> Self {
+        RpcMessage::Request(RpcRequestFuture {
+            array,
+            id,
+            recorder,
+        })
     }
 
     fn response(array: ArrayFuture<R>, id: u32) -> Self {
         RpcMessage::Response(RpcResponseFuture { array, id })
     }
+
+    fn notify(array: ArrayFuture<R>, recorder: Option<SharedRecorder>) -> Self {
+        RpcMessage::Notify(RpcNotifyFuture { array, recorder })
+    }
+}
+
+/// Read the method name shared by requests and notifications. If tracing,
+/// the recorder is told that the method name is about to be read.
+async fn read_method<R: AsyncRead + Unpin>(
+    array: ArrayFuture<R>,
+    recorder: Option<SharedRecorder>,
+) -> IoResult<StringFuture<RpcParamsFuture<R>>> {
+    let method = array
+        .next()
+        .into_option()
+        // Wrap with RpcParamsFuture before potentially returning the ValueFuture
+        .map(|m| MsgPackFuture::new(RpcParamsFuture(m.into_inner())))
+        .ok_or(ProtocolError::MissingField("method"))?
+        .decode()
+        .await?
+        .into_string()
+        .ok_or(ProtocolError::ExpectedMethodString)?;
+    if let Some(recorder) = recorder {
+        recorder.borrow_mut().expect_method(method.len());
+    }
+    Ok(method)
 }
 
 pub struct RpcRequestFuture<R> {
     array: ArrayFuture<R>,
     id: u32,
+    recorder: Option<SharedRecorder>,
 }
 
 impl<R: AsyncRead + Unpin> RpcRequestFuture<R> {
@@ -35,17 +73,24 @@ impl<R: AsyncRead + Unpin> RpcRequestFuture<R> {
         self.id
     }
 
+    /// Handle for responding to this request on a shared writer
+    pub fn responder<W: AsyncWrite + Unpin>(&self, writer: &SharedWriter<W>) -> RpcResponder<W> {
+        writer.responder(self.id)
+    }
+
     pub async fn method(self) -> IoResult<StringFuture<RpcParamsFuture<R>>> {
-        self.array
-            .next()
-            .into_option()
-            // Wrap with RpcParamsFuture before potentially returning the ValueFuture
-            .map(|m| MsgPackFuture::new(RpcParamsFuture(m.into_inner())))
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "array missing method field"))?
-            .decode()
-            .await?
-            .into_string()
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "expected method string"))
+        read_method(self.array, self.recorder).await
+    }
+}
+
+pub struct RpcNotifyFuture<R> {
+    array: ArrayFuture<R>,
+    recorder: Option<SharedRecorder>,
+}
+
+impl<R: AsyncRead + Unpin> RpcNotifyFuture<R> {
+    pub async fn method(self) -> IoResult<StringFuture<RpcParamsFuture<R>>> {
+        read_method(self.array, self.recorder).await
     }
 }
 
@@ -56,16 +101,11 @@ impl<R: AsyncRead + Unpin> RpcParamsFuture<R> {
         self.0
             .last()
             .into_option()
-            .ok_or_else(|| {
-                IoError::new(
-                    ErrorKind::InvalidData,
-                    "array missing params or too many fields",
-                )
-            })?
+            .ok_or(ProtocolError::TooManyFields)?
             .decode()
             .await?
             .into_array()
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "expected params array"))
+            .ok_or_else(|| ProtocolError::ExpectedParamsArray.into())
     }
 }
 
@@ -89,30 +129,100 @@ impl<R: AsyncRead + Unpin> RpcResponseFuture<R> {
         self.id
     }
 
+    /// Consume the rest of the response and return the underlying reader
+    pub async fn skip(self) -> IoResult<R> {
+        self.array.skip().await
+    }
+
+    /// Decode the error field. Once the error value is consumed,
+    /// `RpcErrorFuture` provides access to the result field.
+    pub async fn error(self) -> IoResult<ValueFuture<RpcErrorFuture<R>>> {
+        self.array
+            .next()
+            .into_option()
+            // Wrap with RpcErrorFuture before potentially returning the ValueFuture
+            .map(|m| MsgPackFuture::new(RpcErrorFuture(m.into_inner())))
+            .ok_or(ProtocolError::MissingField("error"))?
+            .decode()
+            .await
+    }
+
+    /// Unwrap the underlying reader positioned at the error field, for a
+    /// decoder that reads the error and result fields itself. It must read
+    /// exactly those two values.
+    pub(crate) fn into_fields(self) -> IoResult<R> {
+        match self.array.len() {
+            0 => Err(ProtocolError::MissingField("error").into()),
+            1 => Err(ProtocolError::MissingField("result").into()),
+            2 => {
+                // Count both fields as read, leaving the reader before them
+                let result = self.array.next().unwrap().into_inner();
+                Ok(result.last().unwrap().into_inner())
+            }
+            _ => Err(ProtocolError::TooManyFields.into()),
+        }
+    }
+
+    /// Read the whole response, returning the result value, or the error
+    /// value as an `RpcError` if it isn't nil
+    pub async fn into_result(self) -> IoResult<(Result<Value, RpcError>, R)>
+    where
+        R: 'static,
+    {
+        let (error, e) = self.error().await?.into_value().await?;
+        let (result, r) = e.result().await?.into_value().await?;
+        let reader = r.finish().await?;
+        let result = if error.is_nil() {
+            Ok(result)
+        } else {
+            Err(RpcError::from_error_value(error))
+        };
+        Ok((result, reader))
+    }
+
+    /// Returns `Ok` with the result value if the error field is nil, or `Err`
+    /// with the error value otherwise. In the error case, the result field can
+    /// still be read from the `RpcErrorFuture` once the error value is
+    /// consumed.
     pub async fn result(
         self,
-    ) -> IoResult<Result<ValueFuture<RpcResultFuture<R>>, ValueFuture<RpcResultFuture<R>>>> {
-        let err = self
-            .array
+    ) -> IoResult<Result<ValueFuture<RpcResultFuture<R>>, ValueFuture<RpcErrorFuture<R>>>> {
+        match self.error().await? {
+            ValueFuture::Nil(e) => e.result().await.map(Ok),
+            err => Ok(Err(err)),
+        }
+    }
+}
+
+/// Response array positioned after the error field
+pub struct RpcErrorFuture<R>(ArrayFuture<R>);
+
+impl<R: AsyncRead + Unpin> RpcErrorFuture<R> {
+    /// Decode the result field
+    pub async fn result(self) -> IoResult<ValueFuture<RpcResultFuture<R>>> {
+        self.0
             .next()
             .into_option()
             // Wrap with RpcResultFuture before potentially returning the ValueFuture
             .map(|m| MsgPackFuture::new(RpcResultFuture(m.into_inner())))
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "array missing error field"))?
+            .ok_or(ProtocolError::MissingField("result"))?
             .decode()
-            .await?;
-        if let ValueFuture::Nil(m) = err {
-            m.0.next()
-                .into_option()
-                // Wrap with RpcResultFuture before potentially returning the ValueFuture
-                .map(|m| MsgPackFuture::new(RpcResultFuture(m.into_inner())))
-                .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "array missing result field"))?
-                .decode()
-                .await
-                .map(Err)
-        } else {
-            Ok(Err(err))
-        }
+            .await
+    }
+
+    /// Skip the result field and return the underlying reader
+    pub async fn finish(self) -> IoResult<R> {
+        self.0.skip().await
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RpcErrorFuture<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        ArrayFuture::poll_read(Pin::new(&mut self.as_mut().0), cx, buf)
     }
 }
 
@@ -138,59 +248,121 @@ impl<R: AsyncRead + Unpin> AsyncRead for RpcResultFuture<R> {
 
 pub struct RpcStream<R> {
     reader: R,
+    /// First byte of the next message, read by `try_next()` to check for EOF
+    peeked: Option<u8>,
+    recorder: Option<SharedRecorder>,
 }
 
 impl<R: AsyncRead + Unpin> RpcStream<R> {
     pub fn new(reader: R) -> Self {
-        RpcStream { reader }
+        RpcStream {
+            reader,
+            peeked: None,
+            recorder: None,
+        }
+    }
+
+    /// Create a stream that reports each message it reads to `trace`
+    pub fn with_trace(reader: R, trace: Trace) -> Self {
+        let mut stream = Self::new(reader);
+        stream.set_trace(trace);
+        stream
+    }
+
+    pub(crate) fn set_trace(&mut self, trace: Trace) {
+        let recorder = Recorder::new(trace, Direction::Incoming);
+        self.recorder = Some(Rc::new(RefCell::new(recorder)));
+    }
+
+    /// Like `next()`, but returns `None` if the peer closed the connection
+    /// cleanly between messages. EOF partway through a message is still an
+    /// `ErrorKind::UnexpectedEof` error.
+    pub async fn try_next(mut self) -> IoResult<Option<RpcMessage<RpcStream<R>>>> {
+        self.end_message();
+        if self.peeked.is_none() {
+            let mut byte = [0];
+            loop {
+                match self.reader.read(&mut byte).await {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if let Some(recorder) = &self.recorder {
+                recorder.borrow_mut().bytes(&byte);
+            }
+            self.peeked = Some(byte[0]);
+        }
+        self.read_message().await.map(Some)
     }
 
     pub async fn next(self) -> IoResult<RpcMessage<RpcStream<R>>> {
+        self.end_message();
+        self.read_message().await
+    }
+
+    /// The previous message, if any, has been read in full
+    fn end_message(&self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.borrow_mut().end();
+        }
+    }
+
+    async fn read_message(self) -> IoResult<RpcMessage<RpcStream<R>>> {
+        let recorder = self.recorder.clone();
+        let describe = |msgtype, id| {
+            if let Some(recorder) = &recorder {
+                recorder.borrow_mut().describe(msgtype, id);
+            }
+        };
         // First, wrap our MsgPackFuture in another instance of RpcStream. Once
         // this message is fully consumed and the underlying reader is returned,
         // the client will be left with this new inner instance of RpcStream
         // pointing at the next message.
-        let msg = MsgPackFuture::new(RpcStream::new(self.reader));
+        let msg = MsgPackFuture::new(RpcStream {
+            reader: self.reader,
+            peeked: self.peeked,
+            recorder: self.recorder.clone(),
+        });
         let a = msg
             .decode()
             .await?
             .into_array()
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "expected array"))?;
-        let ty = a
-            .next()
-            .into_option()
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "msgpack array 0-length"))?;
+            .ok_or(ProtocolError::ExpectedArray)?;
+        let ty = a.next().into_option().ok_or(ProtocolError::EmptyMessage)?;
         let (ty, array) = ty
             .decode()
             .await?
             .into_u64()
-            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "msgtype not int"))?;
+            .ok_or(ProtocolError::MsgTypeNotInt)?;
         match ty {
             0 | 1 => {
                 // Request or Response
-                let msgid = array.next().into_option().ok_or_else(|| {
-                    IoError::new(ErrorKind::InvalidData, "msgpack array 0-length")
-                })?;
+                let msgid = array
+                    .next()
+                    .into_option()
+                    .ok_or(ProtocolError::EmptyMessage)?;
                 let (msgid, array) = msgid
                     .decode()
                     .await?
                     .into_u64()
-                    .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "msgid not int"))?;
-                let msgid = u32::try_from(msgid)
-                    .map_err(|_| IoError::new(ErrorKind::InvalidData, "msgid out of range"))?;
-                match ty {
-                    0 => Ok(RpcMessage::request(array, msgid)),
-                    _ => Ok(RpcMessage::response(array, msgid)),
+                    .ok_or(ProtocolError::MsgIdNotInt)?;
+                let msgid =
+                    u32::try_from(msgid).map_err(|_| ProtocolError::MsgIdOutOfRange(msgid))?;
+                if ty == 0 {
+                    describe(MessageType::Request, Some(msgid));
+                    Ok(RpcMessage::request(array, msgid, self.recorder))
+                } else {
+                    describe(MessageType::Response, Some(msgid));
+                    Ok(RpcMessage::response(array, msgid))
                 }
             }
             2 => {
-                // Notify
-                unimplemented!()
+                describe(MessageType::Notify, None);
+                Ok(RpcMessage::notify(array, self.recorder))
             }
-            ty => Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("invalid msgtype {}", ty),
-            )),
+            ty => Err(ProtocolError::InvalidMsgType(ty).into()),
         }
     }
 }
@@ -201,13 +373,26 @@ impl<R: AsyncRead + Unpin> AsyncRead for RpcStream<R> {
         cx: &mut Context,
         buf: &mut [u8],
     ) -> Poll<IoResult<usize>> {
-        R::poll_read(Pin::new(&mut self.as_mut().reader), cx, buf)
+        if let Some(byte) = self.peeked {
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            buf[0] = byte;
+            self.peeked = None;
+            return Poll::Ready(Ok(1));
+        }
+        let poll = R::poll_read(Pin::new(&mut self.as_mut().reader), cx, buf);
+        if let (Some(recorder), Poll::Ready(Ok(n))) = (&self.recorder, &poll) {
+            recorder.borrow_mut().bytes(&buf[..*n]);
+        }
+        poll
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::decode::ExtractError;
     use rmpv::Value;
     use std::io::Cursor;
 
@@ -299,4 +484,245 @@ mod test {
             .run_until(read_message(stream))
             .unwrap();
     }
+
+    #[test]
+    fn decode_notify() {
+        let notify = Value::Array(vec![
+            2.into(),
+            "redraw".into(),
+            Value::Array(vec!["flush".into()]),
+        ]);
+        let call = Value::Array(vec![
+            0.into(),
+            3.into(),
+            "ping".into(),
+            Value::Array(vec![]),
+        ]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &notify).unwrap();
+        rmpv::encode::write_value(&mut buf, &call).unwrap();
+        let stream = RpcStream::new(Cursor::new(buf));
+
+        async fn read_message<R: AsyncRead + Unpin>(stream: RpcStream<R>) -> IoResult<()> {
+            let stream = match stream.next().await? {
+                RpcMessage::Notify(notify) => {
+                    let (method, params) = notify.method().await?.into_string().await?;
+                    assert_eq!(method, "redraw");
+                    let params = params.params().await?;
+                    assert_eq!(params.len(), 1);
+                    let (param, stream) = params
+                        .last()
+                        .into_option()
+                        .unwrap()
+                        .decode()
+                        .await?
+                        .into_string()
+                        .unwrap()
+                        .into_string()
+                        .await?;
+                    assert_eq!(param, "flush");
+                    stream
+                }
+                _ => panic!("Wrong message type"),
+            };
+            // Reader is positioned at the following message
+            match stream.next().await? {
+                RpcMessage::Request(req) => assert_eq!(req.id(), 3),
+                _ => panic!("Wrong message type"),
+            }
+            Ok(())
+        }
+
+        futures::executor::LocalPool::new()
+            .run_until(read_message(stream))
+            .unwrap();
+    }
+
+    #[test]
+    fn decode_response() {
+        let ok = Value::Array(vec![1.into(), 1.into(), Value::Nil, "done".into()]);
+        let err = Value::Array(vec![1.into(), 2.into(), "failed".into(), Value::Nil]);
+        let both = Value::Array(vec![1.into(), 3.into(), "partial".into(), 42.into()]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &ok).unwrap();
+        rmpv::encode::write_value(&mut buf, &err).unwrap();
+        rmpv::encode::write_value(&mut buf, &both).unwrap();
+        rmpv::encode::write_value(&mut buf, &err).unwrap();
+        let stream = RpcStream::new(Cursor::new(buf));
+
+        async fn read_message(stream: RpcStream<Cursor<Vec<u8>>>) -> IoResult<()> {
+            let stream = match stream.next().await? {
+                RpcMessage::Response(resp) => {
+                    assert_eq!(resp.id(), 1);
+                    let result = match resp.result().await? {
+                        Ok(result) => result,
+                        Err(_) => panic!("expected success"),
+                    };
+                    let (result, result_fut) = result.into_string().unwrap().into_string().await?;
+                    assert_eq!(result, "done");
+                    result_fut.finish().await?
+                }
+                _ => panic!("Wrong message type"),
+            };
+            let stream = match stream.next().await? {
+                RpcMessage::Response(resp) => {
+                    assert_eq!(resp.id(), 2);
+                    let err = match resp.result().await? {
+                        Ok(_) => panic!("expected error"),
+                        Err(err) => err,
+                    };
+                    let (err, err_fut) = err.into_string().unwrap().into_string().await?;
+                    assert_eq!(err, "failed");
+                    // Skip the nil result
+                    err_fut.finish().await?
+                }
+                _ => panic!("Wrong message type"),
+            };
+            let stream = match stream.next().await? {
+                RpcMessage::Response(resp) => {
+                    assert_eq!(resp.id(), 3);
+                    let (err, err_fut) = resp
+                        .error()
+                        .await?
+                        .into_string()
+                        .unwrap()
+                        .into_string()
+                        .await?;
+                    assert_eq!(err, "partial");
+                    let (result, result_fut) = err_fut.result().await?.into_u64().unwrap();
+                    assert_eq!(result, 42);
+                    result_fut.finish().await?
+                }
+                _ => panic!("Wrong message type"),
+            };
+            // Skipping the error value still leaves the reader after the array
+            match stream.next().await? {
+                RpcMessage::Response(resp) => {
+                    let err_fut = resp
+                        .result()
+                        .await?
+                        .err()
+                        .unwrap()
+                        .into_string()
+                        .unwrap()
+                        .skip()
+                        .await?;
+                    let stream = err_fut.finish().await?;
+                    assert_eq!(
+                        stream.reader.position(),
+                        stream.reader.get_ref().len() as u64
+                    );
+                }
+                _ => panic!("Wrong message type"),
+            }
+            Ok(())
+        }
+
+        futures::executor::LocalPool::new()
+            .run_until(read_message(stream))
+            .unwrap();
+    }
+
+    #[test]
+    fn extract_params() {
+        let calls = [
+            Value::Array(vec![
+                0.into(),
+                1.into(),
+                "summon".into(),
+                Value::Array(vec!["husker".into(), 2.into(), Value::Nil]),
+            ]),
+            Value::Array(vec![
+                0.into(),
+                2.into(),
+                "summon".into(),
+                Value::Array(vec!["husker".into(), "two".into(), true.into()]),
+            ]),
+            Value::Array(vec![
+                0.into(),
+                3.into(),
+                "summon".into(),
+                Value::Array(vec!["husker".into()]),
+            ]),
+        ];
+        let mut buf = Vec::new();
+        for call in &calls {
+            rmpv::encode::write_value(&mut buf, call).unwrap();
+        }
+
+        type Params = (String, u32, Option<bool>);
+
+        async fn read_params<R: AsyncRead + Unpin>(
+            stream: RpcStream<R>,
+        ) -> IoResult<(Result<Params, ExtractError>, RpcStream<R>)> {
+            match stream.next().await? {
+                RpcMessage::Request(req) => {
+                    let params = req.method().await?.skip().await?.params().await?;
+                    params.extract::<Params>().await
+                }
+                _ => panic!("Wrong message type"),
+            }
+        }
+
+        async fn read_messages(stream: RpcStream<Cursor<Vec<u8>>>) -> IoResult<()> {
+            let (params, stream) = read_params(stream).await?;
+            assert_eq!(params, Ok(("husker".into(), 2, None)));
+            let (params, stream) = read_params(stream).await?;
+            assert_eq!(params.unwrap_err().to_string(), "element 1: expected u32");
+            let (params, stream) = read_params(stream).await?;
+            assert_eq!(
+                params,
+                Err(ExtractError::Length {
+                    expected: 3,
+                    actual: 1
+                })
+            );
+            assert_eq!(
+                stream.reader.position(),
+                stream.reader.get_ref().len() as u64
+            );
+            Ok(())
+        }
+
+        futures::executor::LocalPool::new()
+            .run_until(read_messages(RpcStream::new(Cursor::new(buf))))
+            .unwrap();
+    }
+
+    #[test]
+    fn clean_close() {
+        let msg = Value::Array(vec![2.into(), "hi".into(), Value::Array(vec![])]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &msg).unwrap();
+
+        async fn read_all(buf: Vec<u8>) -> IoResult<usize> {
+            let mut stream = RpcStream::new(Cursor::new(buf));
+            let mut count = 0;
+            loop {
+                stream = match stream.try_next().await? {
+                    Some(RpcMessage::Notify(notify)) => {
+                        count += 1;
+                        let (_, params) = notify.method().await?.into_string().await?;
+                        params.params().await?.skip().await?
+                    }
+                    Some(_) => panic!("Wrong message type"),
+                    None => return Ok(count),
+                };
+            }
+        }
+
+        let mut two = buf.clone();
+        two.extend_from_slice(&buf);
+        assert_eq!(futures::executor::block_on(read_all(two)).unwrap(), 2);
+        assert_eq!(
+            futures::executor::block_on(read_all(Vec::new())).unwrap(),
+            0
+        );
+
+        // Truncated partway through the second message
+        let mut truncated = buf.clone();
+        truncated.extend_from_slice(&buf[..buf.len() - 1]);
+        let err = futures::executor::block_on(read_all(truncated)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
 }
@@ -0,0 +1,320 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+
+use crate::encode::MsgPackSink;
+use crate::MsgPackOption;
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const NOTIFY: u8 = 2;
+
+/// Write the array length, msgtype and, if present, msgid of a message
+async fn write_header<W: AsyncWrite + Unpin>(
+    writer: W,
+    len: u32,
+    ty: u8,
+    id: Option<u32>,
+) -> IoResult<W> {
+    let w = MsgPackSink::new(writer).write_array_len(len).await?;
+    let w = MsgPackSink::new(w).write_int(ty).await?;
+    match id {
+        Some(id) => MsgPackSink::new(w).write_int(id).await,
+        None => Ok(w),
+    }
+}
+
+/// Write the method name and params array length
+async fn write_method<W: AsyncWrite + Unpin>(
+    writer: W,
+    method: &str,
+    num_params: u32,
+) -> IoResult<RpcParamsSink<W>> {
+    let w = MsgPackSink::new(writer).write_str(method).await?;
+    let writer = MsgPackSink::new(w).write_array_len(num_params).await?;
+    Ok(RpcParamsSink {
+        writer,
+        len: num_params,
+    })
+}
+
+/// Writes a request message: `[0, msgid, method, params]`
+pub struct RpcRequestSink<W> {
+    writer: W,
+    id: u32,
+}
+
+impl<W: AsyncWrite + Unpin> RpcRequestSink<W> {
+    pub fn new(writer: W, id: u32) -> Self {
+        RpcRequestSink { writer, id }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Write the message header and method name, returning a sink for
+    /// exactly `num_params` params
+    pub async fn method(self, method: &str, num_params: u32) -> IoResult<RpcParamsSink<W>> {
+        let w = write_header(self.writer, 4, REQUEST, Some(self.id)).await?;
+        write_method(w, method, num_params).await
+    }
+}
+
+/// Writes a notification message: `[2, method, params]`
+pub struct RpcNotifySink<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> RpcNotifySink<W> {
+    pub fn new(writer: W) -> Self {
+        RpcNotifySink { writer }
+    }
+
+    /// Write the message header and method name, returning a sink for
+    /// exactly `num_params` params
+    pub async fn method(self, method: &str, num_params: u32) -> IoResult<RpcParamsSink<W>> {
+        let w = write_header(self.writer, 3, NOTIFY, None).await?;
+        write_method(w, method, num_params).await
+    }
+}
+
+/// Params array of a request or notification. Each element is written in
+/// turn, and the underlying writer is only returned once all of them have
+/// been written.
+pub struct RpcParamsSink<W> {
+    writer: W,
+    len: u32,
+}
+
+impl<W: AsyncWrite + Unpin> RpcParamsSink<W> {
+    /// Number of params remaining to be written
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn next(mut self) -> MsgPackOption<MsgPackSink<Self>, W> {
+        if self.len > 0 {
+            self.len -= 1;
+            MsgPackOption::Some(MsgPackSink::new(self))
+        } else {
+            MsgPackOption::End(self.writer)
+        }
+    }
+
+    /// If this is the last param, return a sink for it wrapped around the
+    /// underlying writer. Avoids having to call `next()` a final time.
+    ///
+    /// Panics if more than one param is left, since the writer returned
+    /// would be partway through an unfinished message.
+    pub fn last(self) -> MsgPackOption<MsgPackSink<W>, W> {
+        match self.len {
+            0 => MsgPackOption::End(self.writer),
+            1 => MsgPackOption::Some(MsgPackSink::new(self.writer)),
+            len => panic!("last() called with {} params left", len),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for RpcParamsSink<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        W::poll_write(Pin::new(&mut self.as_mut().writer), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_flush(Pin::new(&mut self.as_mut().writer), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_close(Pin::new(&mut self.as_mut().writer), cx)
+    }
+}
+
+/// Writes a response message: `[1, msgid, error, result]`
+pub struct RpcResponseSink<W> {
+    writer: W,
+    id: u32,
+}
+
+impl<W: AsyncWrite + Unpin> RpcResponseSink<W> {
+    pub fn new(writer: W, id: u32) -> Self {
+        RpcResponseSink { writer, id }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Write the message header with a nil error, returning a sink for the
+    /// result value
+    pub async fn ok(self) -> IoResult<MsgPackSink<W>> {
+        let w = write_header(self.writer, 4, RESPONSE, Some(self.id)).await?;
+        MsgPackSink::new(w).write_nil().await.map(MsgPackSink::new)
+    }
+
+    /// Write the message header, returning a sink for the error value. Once
+    /// the error is written, `RpcResultSink` provides the result field.
+    pub async fn error(self) -> IoResult<MsgPackSink<RpcResultSink<W>>> {
+        write_header(self.writer, 4, RESPONSE, Some(self.id))
+            .await
+            .map(|w| MsgPackSink::new(RpcResultSink(w)))
+    }
+}
+
+/// Response array positioned after the error field
+pub struct RpcResultSink<W>(W);
+
+impl<W: AsyncWrite + Unpin> RpcResultSink<W> {
+    /// Sink for a result value to accompany the error
+    pub fn result(self) -> MsgPackSink<W> {
+        MsgPackSink::new(self.0)
+    }
+
+    /// Write a nil result and return the underlying writer
+    pub async fn finish(self) -> IoResult<W> {
+        MsgPackSink::new(self.0).write_nil().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for RpcResultSink<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        W::poll_write(Pin::new(&mut self.as_mut().0), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_flush(Pin::new(&mut self.as_mut().0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_close(Pin::new(&mut self.as_mut().0), cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rmpv::Value;
+
+    fn run_future<R>(f: impl Future<Output = R>) -> R {
+        futures::executor::LocalPool::new().run_until(f)
+    }
+
+    fn to_vec(val: &Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, val).unwrap();
+        buf
+    }
+
+    #[test]
+    fn request() {
+        async fn write(w: Vec<u8>) -> IoResult<Vec<u8>> {
+            let params = RpcRequestSink::new(w, 1).method("summon", 2).await?;
+            let params = params.next().unwrap().write_str("husker").await?;
+            let w = params.last().unwrap().write_str("knights").await?;
+            Ok(w)
+        }
+
+        let expected = Value::Array(vec![
+            0.into(),
+            1.into(),
+            "summon".into(),
+            Value::Array(vec!["husker".into(), "knights".into()]),
+        ]);
+        let w = run_future(write(Vec::new())).unwrap();
+        assert_eq!(w, to_vec(&expected));
+    }
+
+    #[test]
+    fn last_without_params() {
+        let params = run_future(RpcNotifySink::new(Vec::new()).method("quit", 0)).unwrap();
+        let w = params.last().unwrap_end();
+        assert_eq!(
+            w,
+            to_vec(&Value::Array(vec![
+                2.into(),
+                "quit".into(),
+                Value::Array(vec![])
+            ]))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "last() called with 2 params left")]
+    fn last_too_soon() {
+        let params = run_future(RpcNotifySink::new(Vec::new()).method("redraw", 2)).unwrap();
+        let _ = params.last();
+    }
+
+    #[test]
+    fn notify() {
+        async fn write(w: Vec<u8>) -> IoResult<Vec<u8>> {
+            let mut params = RpcNotifySink::new(w).method("redraw", 3).await?;
+            for i in 0..3u8 {
+                params = params.next().unwrap().write_int(i).await?;
+            }
+            Ok(params.next().unwrap_end())
+        }
+
+        let expected = Value::Array(vec![
+            2.into(),
+            "redraw".into(),
+            Value::Array(vec![0.into(), 1.into(), 2.into()]),
+        ]);
+        let w = run_future(write(Vec::new())).unwrap();
+        assert_eq!(w, to_vec(&expected));
+    }
+
+    #[test]
+    fn response() {
+        async fn write(w: Vec<u8>) -> IoResult<Vec<u8>> {
+            let w = RpcResponseSink::new(w, 1)
+                .ok()
+                .await?
+                .write_str("done")
+                .await?;
+            let w = RpcResponseSink::new(w, 2)
+                .error()
+                .await?
+                .write_str("failed")
+                .await?
+                .finish()
+                .await?;
+            let w = RpcResponseSink::new(w, 3)
+                .error()
+                .await?
+                .write_str("partial")
+                .await?
+                .result()
+                .write_int(42u8)
+                .await?;
+            Ok(w)
+        }
+
+        let mut expected = to_vec(&Value::Array(vec![
+            1.into(),
+            1.into(),
+            Value::Nil,
+            "done".into(),
+        ]));
+        expected.extend(to_vec(&Value::Array(vec![
+            1.into(),
+            2.into(),
+            "failed".into(),
+            Value::Nil,
+        ])));
+        expected.extend(to_vec(&Value::Array(vec![
+            1.into(),
+            3.into(),
+            "partial".into(),
+            42.into(),
+        ])));
+        let w = run_future(write(Vec::new())).unwrap();
+        assert_eq!(w, expected);
+    }
+}
@@ -0,0 +1,288 @@
+//! Errors from msgpack-rpc connections.
+//!
+//! msgpack-rpc allows any value as a response's error. By convention this
+//! crate sends application errors as a `[code, message, data]` array, using
+//! the JSON-RPC codes below for errors raised by the RPC layer itself.
+//! Two-element `[code, message]` arrays, as sent by neovim, are accepted too.
+
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use rmpv::Value;
+
+use crate::decode::TypeError;
+use crate::encode::{MsgPackSink, ToMsgPack};
+use crate::rpc::client::RpcResult;
+
+/// The message couldn't be parsed
+pub const PARSE_ERROR: i64 = -32700;
+/// The message isn't a valid request
+pub const INVALID_REQUEST: i64 = -32600;
+/// No handler is registered for the method
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The params don't match what the method takes
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request failed for a reason other than its content
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// The peer sent something that isn't valid msgpack-rpc. Decoding fails with
+/// an `ErrorKind::InvalidData` error wrapping one of these.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtocolError {
+    /// The message isn't an array
+    ExpectedArray,
+    /// The message array is too short to have a msgtype or msgid
+    EmptyMessage,
+    MsgTypeNotInt,
+    InvalidMsgType(u64),
+    MsgIdNotInt,
+    MsgIdOutOfRange(u64),
+    /// The message array ends before the named field
+    MissingField(&'static str),
+    /// The params field isn't the last one in the message array
+    TooManyFields,
+    ExpectedMethodString,
+    ExpectedParamsArray,
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::ExpectedArray => write!(f, "expected array"),
+            ProtocolError::EmptyMessage => write!(f, "msgpack array 0-length"),
+            ProtocolError::MsgTypeNotInt => write!(f, "msgtype not int"),
+            ProtocolError::InvalidMsgType(ty) => write!(f, "invalid msgtype {}", ty),
+            ProtocolError::MsgIdNotInt => write!(f, "msgid not int"),
+            ProtocolError::MsgIdOutOfRange(id) => write!(f, "msgid {} out of range", id),
+            ProtocolError::MissingField(field) => write!(f, "array missing {} field", field),
+            ProtocolError::TooManyFields => write!(f, "array missing params or too many fields"),
+            ProtocolError::ExpectedMethodString => write!(f, "expected method string"),
+            ProtocolError::ExpectedParamsArray => write!(f, "expected params array"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for IoError {
+    fn from(e: ProtocolError) -> Self {
+        IoError::new(ErrorKind::InvalidData, e)
+    }
+}
+
+/// Every msgid is taken by a call that's awaiting a response, or was
+/// cancelled and hasn't been answered yet. Starting a call fails with an
+/// `ErrorKind::Other` error wrapping this.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MsgIdsExhausted;
+
+impl std::fmt::Display for MsgIdsExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "no free msgids")
+    }
+}
+
+impl std::error::Error for MsgIdsExhausted {}
+
+impl From<MsgIdsExhausted> for IoError {
+    fn from(e: MsgIdsExhausted) -> Self {
+        IoError::other(e)
+    }
+}
+
+/// Error response in the conventional `[code, message, data]` encoding
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApplicationError {
+    pub code: i64,
+    pub message: String,
+    /// Nil if there's nothing more to say
+    pub data: Value,
+}
+
+impl ApplicationError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        ApplicationError {
+            code,
+            message: message.into(),
+            data: Value::Nil,
+        }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn method_not_found() -> Self {
+        Self::new(METHOD_NOT_FOUND, "method not found")
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(INTERNAL_ERROR, message)
+    }
+
+    /// Parse an error value, if it's in the conventional encoding
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let fields = value.as_array()?;
+        let (code, message, data) = match fields.as_slice() {
+            [code, message] => (code, message, Value::Nil),
+            [code, message, data] => (code, message, data.clone()),
+            _ => return None,
+        };
+        Some(ApplicationError {
+            code: code.as_i64()?,
+            message: message.as_str()?.into(),
+            data,
+        })
+    }
+
+    pub fn to_value(&self) -> Value {
+        Value::Array(vec![
+            self.code.into(),
+            self.message.as_str().into(),
+            self.data.clone(),
+        ])
+    }
+}
+
+impl std::fmt::Display for ApplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ApplicationError {}
+
+impl ToMsgPack for ApplicationError {
+    async fn to_msgpack<W: AsyncWrite + Unpin>(&self, sink: MsgPackSink<W>) -> IoResult<W> {
+        let w = sink.write_array_len(3).await?;
+        let w = MsgPackSink::new(w).write_int(self.code).await?;
+        let w = MsgPackSink::new(w).write_str(&self.message).await?;
+        MsgPackSink::new(w).write_value(&self.data).await
+    }
+}
+
+/// Why a call failed
+#[derive(Debug)]
+pub enum RpcError {
+    /// Reading or writing the connection failed
+    Transport(IoError),
+    /// The peer broke the protocol
+    Protocol(ProtocolError),
+    /// The peer responded with an error in the conventional encoding
+    Application(ApplicationError),
+    /// The peer responded with some other error value
+    Value(Value),
+    /// The result or error of a typed call isn't of the expected type
+    Decode(TypeError),
+}
+
+impl RpcError {
+    /// Classify the error value of a response
+    pub fn from_error_value(value: Value) -> Self {
+        match ApplicationError::from_value(&value) {
+            Some(e) => RpcError::Application(e),
+            None => RpcError::Value(value),
+        }
+    }
+
+    /// Fold the outcome of a call into a single `Result`
+    pub fn flatten(result: IoResult<RpcResult>) -> Result<Value, RpcError> {
+        match result {
+            Ok(Ok(val)) => Ok(val),
+            Ok(Err(e)) => Err(RpcError::from_error_value(e)),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl From<IoError> for RpcError {
+    fn from(e: IoError) -> Self {
+        let protocol = e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<ProtocolError>());
+        match protocol {
+            Some(p) => RpcError::Protocol(p.clone()),
+            None => RpcError::Transport(e),
+        }
+    }
+}
+
+impl From<RpcError> for IoError {
+    fn from(e: RpcError) -> Self {
+        match e {
+            RpcError::Transport(e) => e,
+            RpcError::Protocol(e) => e.into(),
+            RpcError::Decode(e) => IoError::new(ErrorKind::InvalidData, e),
+            e => IoError::other(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RpcError::Transport(e) => write!(f, "transport error: {}", e),
+            RpcError::Protocol(e) => write!(f, "protocol error: {}", e),
+            RpcError::Application(e) => write!(f, "application error: {}", e),
+            RpcError::Value(v) => write!(f, "error response: {}", v),
+            RpcError::Decode(e) => write!(f, "unexpected response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcError::Transport(e) => Some(e),
+            RpcError::Protocol(e) => Some(e),
+            RpcError::Application(e) => Some(e),
+            RpcError::Value(_) => None,
+            RpcError::Decode(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let conventional = Value::Array(vec![METHOD_NOT_FOUND.into(), "method not found".into()]);
+        match RpcError::from_error_value(conventional) {
+            RpcError::Application(e) => assert_eq!(e, ApplicationError::method_not_found()),
+            e => panic!("unexpected {:?}", e),
+        }
+        match RpcError::from_error_value("oops".into()) {
+            RpcError::Value(v) => assert_eq!(v, "oops".into()),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let err = IoError::from(ProtocolError::InvalidMsgType(7));
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid msgtype 7");
+        match RpcError::from(err) {
+            RpcError::Protocol(p) => assert_eq!(p, ProtocolError::InvalidMsgType(7)),
+            e => panic!("unexpected {:?}", e),
+        }
+        let err = IoError::new(ErrorKind::UnexpectedEof, "eof");
+        assert!(matches!(RpcError::from(err), RpcError::Transport(_)));
+    }
+
+    #[test]
+    fn encode() {
+        let err =
+            ApplicationError::invalid_params("expected 2 elements, got 1").with_data(3.into());
+        let buf =
+            futures::executor::block_on(err.to_msgpack(MsgPackSink::new(Vec::new()))).unwrap();
+        let value = rmpv::decode::read_value(&mut &buf[..]).unwrap();
+        assert_eq!(value, err.to_value());
+        assert_eq!(ApplicationError::from_value(&value), Some(err));
+    }
+}
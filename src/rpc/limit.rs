@@ -0,0 +1,234 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+
+/// Caps on the resources used by an `RpcSession`. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Outgoing calls awaiting a response. Further calls wait until one of
+    /// them completes or is dropped.
+    pub max_pending_calls: Option<usize>,
+    /// Messages queued behind the one being written. Further writes wait
+    /// for room in the queue.
+    pub max_queued_writes: Option<usize>,
+    /// Incoming requests and notifications being handled at once. Reading
+    /// pauses until a handler finishes, pushing back on the peer. Handlers
+    /// waiting on calls to the peer count toward this, and their responses
+    /// can't be read while reading is paused, so leave room for them.
+    pub max_handlers: Option<usize>,
+    /// Notifications queued for each `Subscription`. Once one is full,
+    /// reading pauses until the subscriber catches up, pushing back on the
+    /// peer.
+    pub max_queued_notifications: Option<usize>,
+}
+
+struct State {
+    permits: usize,
+    /// Tasks waiting for a permit, in the order they arrived
+    waiters: VecDeque<(usize, Waker)>,
+    next_key: usize,
+}
+
+impl State {
+    fn wake_next(&mut self) {
+        if self.permits > 0 {
+            if let Some((_, waker)) = self.waiters.front() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Single-threaded async semaphore. Permits are handed out in the order they
+/// were asked for.
+#[derive(Clone)]
+pub struct Semaphore(Rc<RefCell<State>>);
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore(Rc::new(RefCell::new(State {
+            permits,
+            waiters: VecDeque::new(),
+            next_key: 0,
+        })))
+    }
+
+    /// Number of permits that aren't held
+    pub fn available(&self) -> usize {
+        self.0.borrow().permits
+    }
+
+    /// Wait for a permit
+    pub fn acquire(&self) -> Acquire {
+        Acquire {
+            semaphore: self.clone(),
+            key: None,
+        }
+    }
+
+    /// Take a permit if one is available and nobody is waiting for it
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut state = self.0.borrow_mut();
+        if state.permits > 0 && state.waiters.is_empty() {
+            state.permits -= 1;
+            Some(Permit(self.clone()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Future for a `Permit`
+pub struct Acquire {
+    semaphore: Semaphore,
+    key: Option<usize>,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Permit> {
+        let semaphore = self.semaphore.clone();
+        let mut state = semaphore.0.borrow_mut();
+        let first = match (self.key, state.waiters.front()) {
+            (Some(key), Some((front, _))) => key == *front,
+            (None, None) => true,
+            _ => false,
+        };
+        if first && state.permits > 0 {
+            state.permits -= 1;
+            if self.key.take().is_some() {
+                state.waiters.pop_front();
+            }
+            // Further permits may be free for the tasks behind us
+            state.wake_next();
+            return Poll::Ready(Permit(semaphore.clone()));
+        }
+        match self.key {
+            Some(key) => {
+                let waiter = state.waiters.iter_mut().find(|(k, _)| *k == key);
+                waiter.unwrap().1 = cx.waker().clone();
+            }
+            None => {
+                let key = state.next_key;
+                state.next_key = state.next_key.wrapping_add(1);
+                state.waiters.push_back((key, cx.waker().clone()));
+                self.key = Some(key);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            let mut state = self.semaphore.0.borrow_mut();
+            state.waiters.retain(|(k, _)| *k != key);
+            // We may have been woken to take a permit we'll never use
+            state.wake_next();
+        }
+    }
+}
+
+/// Held while using a limited resource. Dropping it hands it to the next
+/// waiter.
+pub struct Permit(Semaphore);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = (self.0).0.borrow_mut();
+        state.permits += 1;
+        state.wake_next();
+    }
+}
+
+/// Buffer for a message read from a peer, failing writes with
+/// `ErrorKind::InvalidData` once it would hold more than `max` bytes
+pub(crate) struct LimitedBuf {
+    buf: Vec<u8>,
+    max: usize,
+    /// What's being buffered, for the error
+    what: &'static str,
+}
+
+impl LimitedBuf {
+    pub(crate) fn new(max: usize, what: &'static str) -> Self {
+        LimitedBuf {
+            buf: Vec::new(),
+            max,
+            what,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl AsyncWrite for LimitedBuf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        if self.buf.len() + buf.len() > self.max {
+            return Poll::Ready(Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("{} longer than {} bytes", self.what, self.max),
+            )));
+        }
+        self.buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn fifo() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let semaphore = Semaphore::new(1);
+        let first = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+
+        let mut second = semaphore.acquire();
+        let mut third = semaphore.acquire();
+        assert!(Pin::new(&mut third).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+
+        drop(first);
+        // Third arrived first
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+        let third = match Pin::new(&mut third).poll(&mut cx) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("permit not handed out"),
+        };
+        assert_eq!(semaphore.available(), 0);
+        drop(third);
+        assert!(Pin::new(&mut second).poll(&mut cx).is_ready());
+        assert_eq!(semaphore.available(), 1);
+    }
+}
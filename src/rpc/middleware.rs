@@ -0,0 +1,56 @@
+use crate::rpc::error::ApplicationError;
+use crate::rpc::server::HandlerFuture;
+
+/// What's known about an incoming request once its method name is read, but
+/// before its params are.
+///
+/// Params are streamed to the handler as they're read, so the size of the
+/// message in bytes isn't known until after it's dispatched. Requests can't
+/// be rejected by size here: `MaxParams` limits the number of params instead,
+/// and limits on bytes belong in the transport.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestInfo<'a> {
+    pub id: u32,
+    pub method: &'a str,
+    pub num_params: usize,
+}
+
+/// Hook run on every request an `RpcServer` receives, whether or not the
+/// method is registered. Interceptors run in the order they were added, and
+/// the first to be added ends up wrapping all the others.
+///
+/// The exception is a method name longer than any the server could
+/// dispatch, or one that isn't UTF-8. There's no name to pass to
+/// interceptors, so the request gets the server's not-found error without
+/// them.
+pub trait Interceptor<R> {
+    /// Check a request before it's dispatched. Returning an error skips the
+    /// params and sends the error as the response without calling the
+    /// handler or any later interceptors.
+    fn before(&self, _req: &RequestInfo) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    /// Wrap the future that reads the params and starts the handler. The
+    /// task it returns, which writes the response, can be wrapped in turn.
+    fn wrap(&self, _req: &RequestInfo, handler: HandlerFuture<R>) -> HandlerFuture<R> {
+        handler
+    }
+}
+
+/// Rejects requests with more than `max` params with an `INVALID_PARAMS`
+/// error. This is as close to a size limit as an interceptor can get.
+pub struct MaxParams(pub usize);
+
+impl<R> Interceptor<R> for MaxParams {
+    fn before(&self, req: &RequestInfo) -> Result<(), ApplicationError> {
+        if req.num_params > self.0 {
+            Err(ApplicationError::invalid_params(format!(
+                "too many params: {} > {}",
+                req.num_params, self.0
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Tracing of the messages passing through an `RpcStream` or `SharedWriter`.
+//!
+//! Messages are streamed rather than buffered, so an event is only emitted
+//! once a message is complete: for incoming messages that's when the next
+//! one is read, and for outgoing messages it's when the writer is handed
+//! back.
+
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// The msgtype of a msgpack-rpc message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Request,
+    Response,
+    Notify,
+}
+
+#[derive(Clone, Debug)]
+pub struct MessageEvent {
+    pub direction: Direction,
+    /// `None` if the message couldn't be decoded, or was written without
+    /// going through the `SharedWriter` message helpers
+    pub msgtype: Option<MessageType>,
+    /// Present for requests and responses
+    pub id: Option<u32>,
+    /// Present for requests and notifications
+    pub method: Option<String>,
+    /// Length of the encoded message
+    pub len: usize,
+    /// When the first byte was read, or the writer was taken
+    pub start: Instant,
+    /// Time from the first byte of the message being read to the last, or
+    /// for outgoing messages, how long the writer was held to write it
+    pub duration: Duration,
+    /// The encoded message, if the `Trace` captures bytes
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl MessageEvent {
+    /// Hex dump of the encoded message, if the `Trace` captures bytes
+    pub fn hex_dump(&self) -> Option<String> {
+        self.bytes.as_ref().map(|b| hex_dump(b))
+    }
+}
+
+/// Receives an event for every message traced
+pub trait Observer {
+    fn message(&self, event: &MessageEvent);
+}
+
+impl<F: Fn(&MessageEvent)> Observer for F {
+    fn message(&self, event: &MessageEvent) {
+        self(event)
+    }
+}
+
+/// Logs each message at debug level, and its hex dump at trace level
+pub struct LogObserver;
+
+impl Observer for LogObserver {
+    fn message(&self, e: &MessageEvent) {
+        log::debug!(
+            "{:?} {:?} id={:?} method={:?}: {} bytes in {:?}",
+            e.direction,
+            e.msgtype,
+            e.id,
+            e.method,
+            e.len,
+            e.duration,
+        );
+        if let Some(dump) = e.hex_dump() {
+            log::trace!("\n{}", dump);
+        }
+    }
+}
+
+/// Observer to attach to a stream or writer, and whether to capture the
+/// encoded messages
+#[derive(Clone)]
+pub struct Trace {
+    observer: Rc<dyn Observer>,
+    capture: bool,
+}
+
+impl Trace {
+    pub fn new(observer: impl Observer + 'static) -> Self {
+        Trace {
+            observer: Rc::new(observer),
+            capture: false,
+        }
+    }
+
+    /// Capture each message's bytes for `MessageEvent::hex_dump()`. This
+    /// buffers every message in full, so it's meant for debugging.
+    pub fn with_hex_dump(mut self) -> Self {
+        self.capture = true;
+        self
+    }
+}
+
+/// Bytes of an incoming method name kept for `MessageEvent::method`. The
+/// rest are dropped, and the name ends in an ellipsis.
+const MAX_METHOD_LEN: usize = 256;
+
+/// The message currently being traced
+struct Partial {
+    start: Instant,
+    last: Instant,
+    msgtype: Option<MessageType>,
+    id: Option<u32>,
+    method: Option<String>,
+    /// Bytes of the method name still to be read
+    method_remaining: usize,
+    /// Up to `MAX_METHOD_LEN` bytes of the method name read so far
+    method_bytes: Vec<u8>,
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+/// Collects a `MessageEvent` from the bytes of a message as they pass
+pub(crate) struct Recorder {
+    trace: Trace,
+    direction: Direction,
+    current: Option<Partial>,
+}
+
+impl Recorder {
+    pub(crate) fn new(trace: Trace, direction: Direction) -> Self {
+        Recorder {
+            trace,
+            direction,
+            current: None,
+        }
+    }
+
+    fn current(&mut self) -> &mut Partial {
+        let now = Instant::now();
+        self.current.get_or_insert_with(|| Partial {
+            start: now,
+            last: now,
+            msgtype: None,
+            id: None,
+            method: None,
+            method_remaining: 0,
+            method_bytes: Vec::new(),
+            len: 0,
+            bytes: Vec::new(),
+        })
+    }
+
+    /// Finish the current message, if any, and start timing a new one
+    pub(crate) fn begin(&mut self) {
+        self.end();
+        self.current();
+    }
+
+    pub(crate) fn bytes(&mut self, buf: &[u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let capture = self.trace.capture;
+        let current = self.current();
+        current.last = Instant::now();
+        current.len += buf.len();
+        if capture {
+            current.bytes.extend_from_slice(buf);
+        }
+        if current.method_remaining > 0 {
+            let n = current.method_remaining.min(buf.len());
+            let keep = n.min(MAX_METHOD_LEN - current.method_bytes.len());
+            current.method_bytes.extend_from_slice(&buf[..keep]);
+            current.method_remaining -= n;
+            if current.method_remaining == 0 {
+                let bytes = std::mem::take(&mut current.method_bytes);
+                let mut method = String::from_utf8_lossy(&bytes).into_owned();
+                // Once the cap is reached every later byte is dropped, so
+                // the last of them shows whether any were
+                if keep < n {
+                    method.push('…');
+                }
+                current.method = Some(method);
+            }
+        }
+    }
+
+    pub(crate) fn describe(&mut self, msgtype: MessageType, id: Option<u32>) {
+        let current = self.current();
+        current.msgtype = Some(msgtype);
+        current.id = id;
+    }
+
+    pub(crate) fn set_method(&mut self, method: &str) {
+        self.current().method = Some(method.into());
+    }
+
+    /// The next `len` bytes are the method name
+    pub(crate) fn expect_method(&mut self, len: usize) {
+        let current = self.current();
+        if len == 0 {
+            current.method = Some(String::new());
+        } else {
+            current.method_remaining = len;
+        }
+    }
+
+    /// Emit the event for the current message, unless nothing was written
+    pub(crate) fn end(&mut self) {
+        let capture = self.trace.capture;
+        if let Some(p) = self.current.take() {
+            if p.len == 0 {
+                return;
+            }
+            let event = MessageEvent {
+                direction: self.direction,
+                msgtype: p.msgtype,
+                id: p.id,
+                method: p.method,
+                len: p.len,
+                start: p.start,
+                duration: match self.direction {
+                    Direction::Incoming => p.last - p.start,
+                    Direction::Outgoing => p.start.elapsed(),
+                },
+                bytes: if capture { Some(p.bytes) } else { None },
+            };
+            self.trace.observer.message(&event);
+        }
+    }
+}
+
+/// Format bytes 16 to a line, with their offset and printable characters
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", i * 16);
+        for b in line {
+            let _ = write!(out, " {:02x}", b);
+        }
+        for _ in line.len()..16 {
+            out.push_str("   ");
+        }
+        out.push_str("  |");
+        for &b in line {
+            out.push(if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::decode::{RpcMessage, RpcStream};
+    use crate::rpc::shared::SharedWriter;
+    use futures::io::Result as IoResult;
+    use rmpv::Value;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    fn collect() -> (Trace, Rc<RefCell<Vec<MessageEvent>>>) {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        let trace = Trace::new(move |e: &MessageEvent| seen.borrow_mut().push(e.clone()));
+        (trace, events)
+    }
+
+    #[test]
+    fn incoming() {
+        let msgs = [
+            Value::Array(vec![
+                0.into(),
+                5.into(),
+                "add".into(),
+                Value::Array(vec![1.into(), 2.into()]),
+            ]),
+            Value::Array(vec![2.into(), "redraw".into(), Value::Array(vec![])]),
+        ];
+        let mut bufs = Vec::new();
+        for m in &msgs {
+            let mut buf = Vec::new();
+            rmpv::encode::write_value(&mut buf, m).unwrap();
+            bufs.push(buf);
+        }
+
+        async fn read_all(mut stream: RpcStream<Cursor<Vec<u8>>>) -> IoResult<()> {
+            loop {
+                let (_, params) = match stream.try_next().await? {
+                    Some(RpcMessage::Request(req)) => req.method().await?.into_string().await?,
+                    Some(RpcMessage::Notify(n)) => n.method().await?.into_string().await?,
+                    Some(RpcMessage::Response(_)) => panic!("Wrong message type"),
+                    None => return Ok(()),
+                };
+                stream = params.params().await?.skip().await?;
+            }
+        }
+
+        let (trace, events) = collect();
+        let stream = RpcStream::with_trace(Cursor::new(bufs.concat()), trace.with_hex_dump());
+        futures::executor::block_on(read_all(stream)).unwrap();
+
+        let events = events.borrow();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].direction, Direction::Incoming);
+        assert_eq!(events[0].msgtype, Some(MessageType::Request));
+        assert_eq!(events[0].id, Some(5));
+        assert_eq!(events[0].method.as_ref().unwrap(), "add");
+        assert_eq!(events[0].len, bufs[0].len());
+        assert_eq!(events[0].bytes.as_ref().unwrap(), &bufs[0]);
+        assert_eq!(events[1].msgtype, Some(MessageType::Notify));
+        assert_eq!(events[1].id, None);
+        assert_eq!(events[1].method.as_ref().unwrap(), "redraw");
+        assert_eq!(events[1].len, bufs[1].len());
+    }
+
+    #[test]
+    fn long_method() {
+        let method = "a".repeat(MAX_METHOD_LEN + 1);
+        let msg = Value::Array(vec![2.into(), method.into(), Value::Array(vec![])]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &msg).unwrap();
+
+        let (trace, events) = collect();
+        let stream = RpcStream::with_trace(Cursor::new(buf), trace);
+        futures::executor::block_on(async {
+            let n = match stream.try_next().await? {
+                Some(RpcMessage::Notify(n)) => n,
+                _ => panic!("Wrong message type"),
+            };
+            let (_, params) = n.method().await?.into_string().await?;
+            let stream = params.params().await?.skip().await?;
+            assert!(stream.try_next().await?.is_none());
+            IoResult::Ok(())
+        })
+        .unwrap();
+
+        let method = events.borrow()[0].method.clone().unwrap();
+        assert_eq!(method, "a".repeat(MAX_METHOD_LEN) + "…");
+    }
+
+    #[test]
+    fn outgoing() {
+        let (trace, events) = collect();
+        let shared = SharedWriter::new(Vec::new()).with_trace(trace);
+        let responder = shared.responder(3);
+        futures::executor::block_on(async {
+            let params = shared.request(1, "ping", 0).await?;
+            params.next().unwrap_end().release().await?;
+            drop(responder);
+            shared
+                .notify("hi", 0)
+                .await?
+                .next()
+                .unwrap_end()
+                .release()
+                .await
+        })
+        .unwrap();
+
+        let events = events.borrow();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.msgtype.unwrap(), e.id, e.method.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (MessageType::Request, Some(1), Some("ping")),
+                // Written on behalf of the dropped responder
+                (MessageType::Response, Some(3), None),
+                (MessageType::Notify, None, Some("hi")),
+            ]
+        );
+        assert!(events.iter().all(|e| e.direction == Direction::Outgoing));
+        assert!(events[0].bytes.is_none());
+    }
+
+    #[test]
+    fn dump() {
+        let dump = hex_dump(b"\x93\x00\x01hello, world!\xc0");
+        assert_eq!(
+            dump,
+            "00000000  93 00 01 68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21  |...hello, world!|\n\
+             00000010  c0                                               |.|\n"
+        );
+    }
+}
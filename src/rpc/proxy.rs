@@ -0,0 +1,535 @@
+//! Forwarding of requests from any number of upstream connections to one
+//! downstream peer.
+//!
+//! Each forwarded request is given a fresh msgid on the downstream
+//! connection, so ids chosen independently by different upstream peers
+//! can't collide, and the response is sent back to the upstream peer under
+//! its original id.
+//!
+//! The params of a forwarded message are read in full, up to
+//! `MAX_PARAMS_LEN` bytes, before the downstream writer is taken, so an
+//! upstream peer that stalls or goes away partway through a message can't
+//! hold up or garble the downstream connection. Results and errors are
+//! copied as they are read rather than buffered: a downstream peer that
+//! fails partway through a response ends `run_downstream()` anyway.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::io::Error as IoError;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+
+use crate::decode::{ArrayFuture, ValueFuture};
+use crate::rpc::decode::{
+    RpcMessage, RpcNotifyFuture, RpcRequestFuture, RpcResponseFuture, RpcStream,
+};
+use crate::rpc::encode::RpcParamsSink;
+use crate::rpc::error::ApplicationError;
+use crate::rpc::limit::LimitedBuf;
+use crate::rpc::shared::{RpcResponder, SharedWriter, SharedWriterGuard};
+use crate::MsgPackOption;
+
+/// Most bytes of params buffered for a forwarded message. Longer ones fail
+/// the upstream connection with `ErrorKind::InvalidData`.
+pub const MAX_PARAMS_LEN: usize = 16 * 1024 * 1024;
+
+/// Longest method name forwarded. Requests with longer names are answered
+/// with `METHOD_NOT_FOUND`, and notifications with them are dropped.
+pub const MAX_METHOD_LEN: usize = 1024;
+
+struct Routes<U> {
+    next_id: u32,
+    /// Responders for forwarded requests, by downstream msgid
+    pending: HashMap<u32, RpcResponder<U>>,
+}
+
+/// Forwards requests and notifications to a downstream peer, and routes its
+/// responses back. `U` is the writer type of the upstream connections.
+pub struct Proxy<W, U> {
+    downstream: SharedWriter<W>,
+    routes: Rc<RefCell<Routes<U>>>,
+}
+
+impl<W, U> Clone for Proxy<W, U> {
+    fn clone(&self) -> Self {
+        Proxy {
+            downstream: self.downstream.clone(),
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+impl<W, U> Proxy<W, U>
+where
+    W: AsyncWrite + Unpin,
+    U: AsyncWrite + Unpin,
+{
+    pub fn new(downstream: SharedWriter<W>) -> Self {
+        Proxy {
+            downstream,
+            routes: Rc::new(RefCell::new(Routes {
+                next_id: 0,
+                pending: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Number of forwarded requests still waiting for a response
+    pub fn pending(&self) -> usize {
+        self.routes.borrow().pending.len()
+    }
+
+    /// Forward a request from an upstream peer, which will be responded to
+    /// on `upstream` once the downstream peer responds. Returns the reader
+    /// positioned after the request.
+    pub async fn forward_request<R>(
+        &self,
+        req: RpcRequestFuture<R>,
+        upstream: &SharedWriter<U>,
+    ) -> IoResult<R>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let responder = req.responder(upstream);
+        let method = req.method().await?;
+        if method.len() > MAX_METHOD_LEN {
+            log::warn!(
+                "not forwarding request with {} byte method name",
+                method.len()
+            );
+            let r = method.skip().await?.params().await?.skip().await?;
+            responder
+                .respond_error(&ApplicationError::method_not_found())
+                .await?;
+            return Ok(r);
+        }
+        let (method, params) = method.into_string().await?;
+        let (params, r) = Params::read(params.params().await?).await?;
+        let id = {
+            let mut routes = self.routes.borrow_mut();
+            let mut id = routes.next_id;
+            while routes.pending.contains_key(&id) {
+                id = id.wrapping_add(1);
+            }
+            routes.next_id = id.wrapping_add(1);
+            routes.pending.insert(id, responder);
+            id
+        };
+        let result = async {
+            let sink = self.downstream.request(id, &method, params.len()).await?;
+            params.write(sink).await?.release().await
+        }
+        .await;
+        if let Err(e) = result {
+            // Dropping the responder reports the failure upstream
+            self.routes.borrow_mut().pending.remove(&id);
+            return Err(e);
+        }
+        Ok(r)
+    }
+
+    /// Forward a notification from an upstream peer, returning the reader
+    /// positioned after it
+    pub async fn forward_notify<R>(&self, notify: RpcNotifyFuture<R>) -> IoResult<R>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let method = notify.method().await?;
+        if method.len() > MAX_METHOD_LEN {
+            log::warn!(
+                "not forwarding notification with {} byte method name",
+                method.len()
+            );
+            return method.skip().await?.params().await?.skip().await;
+        }
+        let (method, params) = method.into_string().await?;
+        let (params, r) = Params::read(params.params().await?).await?;
+        let sink = self.downstream.notify(&method, params.len()).await?;
+        params.write(sink).await?.release().await?;
+        Ok(r)
+    }
+
+    /// Send a response from the downstream peer back to the upstream peer
+    /// that made the request, returning the reader positioned after it.
+    ///
+    /// Failing to write to the upstream peer, which has most likely gone
+    /// away, only loses that response. The rest of it is still read.
+    pub async fn handle_response<R>(&self, resp: RpcResponseFuture<R>) -> IoResult<R>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let responder = self.routes.borrow_mut().pending.remove(&resp.id());
+        let responder = match responder {
+            Some(responder) => responder,
+            None => {
+                log::warn!("skipping response to unknown msgid {}", resp.id());
+                return resp.skip().await;
+            }
+        };
+        let id = responder.id();
+        match resp.error().await? {
+            ValueFuture::Nil(e) => {
+                let result = e.result().await?;
+                match responder.respond_ok().await {
+                    Ok(sink) => {
+                        let mut w = Upstream::new(sink.into_inner());
+                        let r = result.copy_to(&mut w).await?;
+                        w.release(id).await;
+                        r.finish().await
+                    }
+                    Err(e) => {
+                        upstream_failed(id, e);
+                        result.skip().await?.finish().await
+                    }
+                }
+            }
+            error => match responder.respond_err().await {
+                Ok(sink) => {
+                    let mut w = Upstream::new(sink.into_inner());
+                    let e = error.copy_to(&mut w).await?;
+                    let result = e.result().await?;
+                    let mut w = Upstream {
+                        writer: w.writer.result().into_inner(),
+                        error: w.error,
+                    };
+                    let r = result.copy_to(&mut w).await?;
+                    w.release(id).await;
+                    r.finish().await
+                }
+                Err(e) => {
+                    upstream_failed(id, e);
+                    error.skip().await?.finish().await
+                }
+            },
+        }
+    }
+
+    /// Forward everything read from an upstream peer until it closes the
+    /// connection
+    pub async fn run_upstream<R>(
+        &self,
+        mut stream: RpcStream<R>,
+        upstream: SharedWriter<U>,
+    ) -> IoResult<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            stream = match stream.try_next().await? {
+                Some(RpcMessage::Request(req)) => self.forward_request(req, &upstream).await?,
+                Some(RpcMessage::Notify(notify)) => self.forward_notify(notify).await?,
+                Some(RpcMessage::Response(resp)) => {
+                    log::warn!("skipping response from upstream to msgid {}", resp.id());
+                    resp.skip().await?
+                }
+                None => return Ok(()),
+            };
+        }
+    }
+
+    /// Route responses from the downstream peer until it closes the
+    /// connection. Requests from the downstream peer are answered with
+    /// `METHOD_NOT_FOUND`, and notifications are dropped.
+    ///
+    /// Requests still waiting when the connection closes are answered with
+    /// an error.
+    pub async fn run_downstream<R>(&self, mut stream: RpcStream<R>) -> IoResult<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let result = async {
+            loop {
+                stream = match stream.try_next().await? {
+                    Some(RpcMessage::Response(resp)) => self.handle_response(resp).await?,
+                    Some(RpcMessage::Request(req)) => {
+                        let responder = req.responder(&self.downstream);
+                        let r = req
+                            .method()
+                            .await?
+                            .skip()
+                            .await?
+                            .params()
+                            .await?
+                            .skip()
+                            .await?;
+                        responder
+                            .respond_error(&ApplicationError::method_not_found())
+                            .await?;
+                        r
+                    }
+                    Some(RpcMessage::Notify(notify)) => {
+                        notify
+                            .method()
+                            .await?
+                            .skip()
+                            .await?
+                            .params()
+                            .await?
+                            .skip()
+                            .await?
+                    }
+                    None => return Ok(()),
+                };
+            }
+        }
+        .await;
+        // Dropping the responders queues error responses upstream
+        self.routes.borrow_mut().pending.clear();
+        result
+    }
+}
+
+/// Params of an upstream message, read before the downstream writer is
+/// taken
+struct Params {
+    buf: Vec<u8>,
+    /// Where each param ends in `buf`
+    ends: Vec<usize>,
+}
+
+impl Params {
+    async fn read<R: AsyncRead + Unpin>(params: ArrayFuture<R>) -> IoResult<(Self, R)> {
+        let mut buf = LimitedBuf::new(MAX_PARAMS_LEN, "forwarded params");
+        let mut ends = Vec::new();
+        let mut params = params;
+        loop {
+            match params.next() {
+                MsgPackOption::Some(param) => {
+                    params = param.copy_to(&mut buf).await?;
+                    ends.push(buf.len());
+                }
+                MsgPackOption::End(r) => {
+                    let buf = buf.into_inner();
+                    return Ok((Params { buf, ends }, r));
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> u32 {
+        self.ends.len() as u32
+    }
+
+    /// Write the params into a downstream message, returning its writer
+    async fn write<W: AsyncWrite + Unpin>(self, sink: RpcParamsSink<W>) -> IoResult<W> {
+        let mut sink = sink;
+        let mut start = 0;
+        for end in self.ends {
+            let mut w = sink.next().unwrap().into_inner();
+            w.write_all(&self.buf[start..end]).await?;
+            sink = w;
+            start = end;
+        }
+        Ok(sink.next().unwrap_end())
+    }
+}
+
+fn upstream_failed(id: u32, error: IoError) {
+    log::warn!("dropping response to upstream msgid {}: {}", id, error);
+}
+
+/// Writer for a response to an upstream peer. Once a write fails, the rest
+/// of the response is discarded instead, so that the downstream reader
+/// isn't left partway through a message.
+struct Upstream<W> {
+    writer: W,
+    error: Option<IoError>,
+}
+
+impl<W: AsyncWrite + Unpin> Upstream<W> {
+    fn new(writer: W) -> Self {
+        Upstream {
+            writer,
+            error: None,
+        }
+    }
+}
+
+impl<U: AsyncWrite + Unpin> Upstream<SharedWriterGuard<U>> {
+    async fn release(self, id: u32) {
+        let result = match self.error {
+            Some(e) => Err(e),
+            None => self.writer.release().await,
+        };
+        if let Err(e) = result {
+            upstream_failed(id, e);
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Upstream<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        if self.error.is_none() {
+            match Pin::new(&mut self.writer).poll_write(cx, buf) {
+                Poll::Ready(Err(e)) => self.error = Some(e),
+                poll => return poll,
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        if self.error.is_none() {
+            match Pin::new(&mut self.writer).poll_flush(cx) {
+                Poll::Ready(Err(e)) => self.error = Some(e),
+                poll => return poll,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        if self.error.is_none() {
+            return Pin::new(&mut self.writer).poll_close(cx);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rmpv::Value;
+    use std::io::Cursor;
+
+    fn encode(msgs: &[Value]) -> Cursor<Vec<u8>> {
+        let mut buf = Vec::new();
+        for m in msgs {
+            rmpv::encode::write_value(&mut buf, m).unwrap();
+        }
+        Cursor::new(buf)
+    }
+
+    fn decode(writer: &SharedWriter<Vec<u8>>) -> Vec<Value> {
+        let mut guard = futures::executor::block_on(writer.take()).unwrap();
+        let buf = std::mem::take(guard.get_mut());
+        let mut r = &buf[..];
+        let mut msgs = Vec::new();
+        while !r.is_empty() {
+            msgs.push(rmpv::decode::read_value(&mut r).unwrap());
+        }
+        msgs
+    }
+
+    fn request(id: u32, method: &str, params: Vec<Value>) -> Value {
+        Value::Array(vec![0.into(), id.into(), method.into(), params.into()])
+    }
+
+    fn response(id: u32, error: Value, result: Value) -> Value {
+        Value::Array(vec![1.into(), id.into(), error, result])
+    }
+
+    #[test]
+    fn forward() {
+        let downstream = SharedWriter::new(Vec::new());
+        let proxy = Proxy::new(downstream.clone());
+        let upstreams = [SharedWriter::new(Vec::new()), SharedWriter::new(Vec::new())];
+        let nested = Value::Map(vec![("k".into(), vec![Value::from(1)].into())]);
+
+        // Both upstream peers use msgid 7
+        let mut pool = futures::executor::LocalPool::new();
+        for (i, upstream) in upstreams.iter().enumerate() {
+            let msgs = [
+                request(7, "get", vec![i.into(), nested.clone()]),
+                Value::Array(vec![2.into(), "log".into(), vec![Value::from(i)].into()]),
+            ];
+            pool.run_until(proxy.run_upstream(RpcStream::new(encode(&msgs)), upstream.clone()))
+                .unwrap();
+        }
+        assert_eq!(proxy.pending(), 2);
+        assert_eq!(
+            decode(&downstream),
+            vec![
+                request(0, "get", vec![0.into(), nested.clone()]),
+                Value::Array(vec![2.into(), "log".into(), vec![Value::from(0)].into()]),
+                request(1, "get", vec![1.into(), nested.clone()]),
+                Value::Array(vec![2.into(), "log".into(), vec![Value::from(1)].into()]),
+            ]
+        );
+
+        let error = Value::Array(vec![(-32603).into(), "failed".into()]);
+        let msgs = [
+            response(1, error.clone(), Value::Nil),
+            response(9, Value::Nil, Value::Nil),
+            response(0, Value::Nil, nested.clone()),
+        ];
+        pool.run_until(proxy.run_downstream(RpcStream::new(encode(&msgs))))
+            .unwrap();
+        assert_eq!(proxy.pending(), 0);
+        assert_eq!(decode(&upstreams[0]), vec![response(7, Value::Nil, nested)]);
+        assert_eq!(decode(&upstreams[1]), vec![response(7, error, Value::Nil)]);
+    }
+
+    #[test]
+    fn long_method() {
+        let downstream = SharedWriter::new(Vec::new());
+        let proxy = Proxy::new(downstream.clone());
+        let upstream = SharedWriter::new(Vec::new());
+        let long = "a".repeat(MAX_METHOD_LEN + 1);
+        let msgs = [
+            request(1, &long, vec![1.into()]),
+            Value::Array(vec![2.into(), long.as_str().into(), Value::Array(vec![])]),
+            request(2, "get", vec![]),
+        ];
+        futures::executor::LocalPool::new()
+            .run_until(proxy.run_upstream(RpcStream::new(encode(&msgs)), upstream.clone()))
+            .unwrap();
+        assert_eq!(decode(&downstream), vec![request(0, "get", vec![])]);
+        let not_found = ApplicationError::method_not_found().to_value();
+        assert_eq!(decode(&upstream), vec![response(1, not_found, Value::Nil)]);
+    }
+
+    #[test]
+    fn upstream_closed_mid_params() {
+        let downstream = SharedWriter::new(Vec::new());
+        let proxy = Proxy::new(downstream.clone());
+        let upstream = SharedWriter::new(Vec::new());
+        let mut input = encode(&[
+            request(1, "get", vec![1.into()]),
+            request(2, "put", vec!["key".into(), "value".into()]),
+        ])
+        .into_inner();
+        // Cut off partway through the second param of the second request
+        input.truncate(input.len() - 2);
+
+        let mut pool = futures::executor::LocalPool::new();
+        let stream = RpcStream::new(Cursor::new(input));
+        let e = pool
+            .run_until(proxy.run_upstream(stream, upstream.clone()))
+            .unwrap_err();
+        assert_eq!(e.kind(), futures::io::ErrorKind::UnexpectedEof);
+        // Only the complete request made it downstream
+        assert_eq!(decode(&downstream), vec![request(0, "get", vec![1.into()])]);
+        assert_eq!(proxy.pending(), 1);
+
+        // The cut off request was answered with an error, and the other is
+        // once the downstream peer goes away
+        pool.run_until(proxy.run_downstream(RpcStream::new(encode(&[]))))
+            .unwrap();
+        let msgs = decode(&upstream);
+        let ids: Vec<_> = msgs.iter().map(|m| m[1].clone()).collect();
+        assert_eq!(ids, vec![Value::from(2), Value::from(1)]);
+        assert!(msgs.iter().all(|m| !m[2].is_nil()));
+    }
+
+    #[test]
+    fn downstream_closed() {
+        let proxy = Proxy::new(SharedWriter::new(Vec::new()));
+        let upstream = SharedWriter::new(Vec::new());
+        let msgs = [request(3, "get", vec![])];
+        let mut pool = futures::executor::LocalPool::new();
+        pool.run_until(proxy.run_upstream(RpcStream::new(encode(&msgs)), upstream.clone()))
+            .unwrap();
+        pool.run_until(proxy.run_downstream(RpcStream::new(encode(&[]))))
+            .unwrap();
+        assert_eq!(proxy.pending(), 0);
+        let msgs = decode(&upstream);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0][1], 3.into());
+        assert!(!msgs[0][2].is_nil());
+    }
+}
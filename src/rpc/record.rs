@@ -0,0 +1,527 @@
+//! Recording RPC sessions and replaying them against a server.
+//!
+//! A recording is written as a msgpack header `["msgpack-rpc-log", 1]`
+//! followed by one `[direction, offset, message]` array per message, where
+//! direction is 0 for incoming and 1 for outgoing, offset is the number of
+//! microseconds from the start of the recording to the start of the message,
+//! and message is the encoded message as a bin.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::io::BufReader;
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use futures::task::{waker_ref, ArcWake};
+use rmpv::Value;
+
+use crate::decode::MsgPackFuture;
+use crate::encode::MsgPackSink;
+use crate::rpc::decode::RpcStream;
+use crate::rpc::observe::{Direction, MessageEvent, Trace};
+use crate::rpc::server::RpcServer;
+use crate::rpc::session::RpcSession;
+
+const LOG_MAGIC: &str = "msgpack-rpc-log";
+const LOG_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub direction: Direction,
+    /// Time from the start of the recording to the start of the message
+    pub offset: Duration,
+    /// The encoded message
+    pub message: Vec<u8>,
+}
+
+/// The messages of a session, in the order they started
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    entries: Vec<LogEntry>,
+}
+
+impl Recording {
+    pub fn new(entries: Vec<LogEntry>) -> Self {
+        Recording { entries }
+    }
+
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: W) -> IoResult<W> {
+        let w = MsgPackSink::new(writer).write_array_len(2).await?;
+        let w = MsgPackSink::new(w).write_str(LOG_MAGIC).await?;
+        let mut w = MsgPackSink::new(w).write_int(LOG_VERSION).await?;
+        for entry in &self.entries {
+            let direction: u8 = match entry.direction {
+                Direction::Incoming => 0,
+                Direction::Outgoing => 1,
+            };
+            let offset = micros(entry.offset).as_micros() as u64;
+            w = MsgPackSink::new(w).write_array_len(3).await?;
+            w = MsgPackSink::new(w).write_int(direction).await?;
+            w = MsgPackSink::new(w).write_int(offset).await?;
+            w = MsgPackSink::new(w).write_bin(&entry.message).await?;
+        }
+        w.flush().await?;
+        Ok(w)
+    }
+
+    /// Read a recording to the end of `reader`, one entry at a time
+    pub async fn read<R: AsyncRead + Unpin>(reader: R) -> IoResult<Self> {
+        let mut r = read_header(BufReader::new(reader)).await?;
+        let mut entries = Vec::new();
+        while !at_eof(&mut r).await? {
+            let (entry, next) = read_entry(r).await?;
+            entries.push(entry);
+            r = next;
+        }
+        Ok(Recording { entries })
+    }
+
+    /// Feed the incoming messages to `server` and collect what it sends
+    /// back, for comparison with the outgoing messages recorded.
+    ///
+    /// Replay is paced by the order of the recording rather than its timing:
+    /// each incoming message is held back until the server has sent as many
+    /// messages as had been sent before it in the recording, or until the
+    /// server can make no more progress without it. This way responses to
+    /// calls the server makes to the peer arrive after the calls do. The
+    /// server's handlers mustn't wait on anything outside the session, or
+    /// replay gives up on them.
+    pub async fn replay(
+        &self,
+        server: RpcServer<RpcStream<ReplayReader>, Vec<u8>>,
+    ) -> IoResult<Replay> {
+        let gate = Rc::new(RefCell::new(Gate::default()));
+        let mut sent = 0;
+        let mut incoming = VecDeque::new();
+        for entry in &self.entries {
+            match entry.direction {
+                Direction::Incoming => incoming.push_back((sent, entry.message.clone())),
+                Direction::Outgoing => sent += 1,
+            }
+        }
+        let reader = ReplayReader {
+            messages: incoming,
+            pos: 0,
+            gate: gate.clone(),
+        };
+        let outgoing = gate.clone();
+        let trace = Trace::new(move |e: &MessageEvent| {
+            if e.direction == Direction::Outgoing {
+                let mut gate = outgoing.borrow_mut();
+                gate.sent.push(e.bytes.clone().unwrap());
+                if let Some(waker) = gate.waker.take() {
+                    waker.wake();
+                }
+            }
+        })
+        .with_hex_dump();
+        let session = RpcSession::new(reader, Vec::new(), server).with_trace(trace);
+
+        let run = session.run();
+        futures::pin_mut!(run);
+        future::poll_fn(|cx| {
+            let stall = Arc::new(StallWaker {
+                woken: AtomicBool::new(false),
+                waker: cx.waker().clone(),
+            });
+            let waker = waker_ref(&stall);
+            if let Poll::Ready(result) = run.as_mut().poll(&mut Context::from_waker(&waker)) {
+                return Poll::Ready(result);
+            }
+            if stall.woken.load(Ordering::SeqCst) {
+                return Poll::Pending;
+            }
+            // Nothing can make progress, so let the next message through if
+            // that's what's holding things up
+            let mut gate = gate.borrow_mut();
+            match gate.waker.take() {
+                Some(waker) => {
+                    gate.forced = true;
+                    waker.wake();
+                    Poll::Pending
+                }
+                None => Poll::Ready(Err(IoError::other(
+                    "replay stalled with handlers still running",
+                ))),
+            }
+        })
+        .await?;
+
+        let expected = self
+            .entries
+            .iter()
+            .filter(|e| e.direction == Direction::Outgoing)
+            .map(|e| decode_message(&e.message))
+            .collect::<IoResult<_>>()?;
+        let actual = gate
+            .borrow()
+            .sent
+            .iter()
+            .map(|m| decode_message(m))
+            .collect::<IoResult<_>>()?;
+        Ok(Replay { expected, actual })
+    }
+}
+
+async fn read_header<R: AsyncRead + Unpin>(reader: R) -> IoResult<R> {
+    let invalid = || IoError::new(ErrorKind::InvalidData, "not a msgpack-rpc log");
+    let a = MsgPackFuture::new(reader)
+        .decode()
+        .await?
+        .into_array()
+        .filter(|a| a.len() == 2)
+        .ok_or_else(invalid)?;
+    let (magic, a) = a
+        .next()
+        .into_option()
+        .unwrap()
+        .decode()
+        .await?
+        .into_string()
+        .ok_or_else(invalid)?
+        .into_string()
+        .await?;
+    let (version, r) = a
+        .last()
+        .into_option()
+        .unwrap()
+        .decode()
+        .await?
+        .into_u64()
+        .ok_or_else(invalid)?;
+    if magic != LOG_MAGIC {
+        return Err(invalid());
+    }
+    if version != u64::from(LOG_VERSION) {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("unsupported log version {}", version),
+        ));
+    }
+    Ok(r)
+}
+
+async fn read_entry<R: AsyncRead + Unpin>(reader: R) -> IoResult<(LogEntry, R)> {
+    let invalid = || IoError::new(ErrorKind::InvalidData, "invalid log entry");
+    let a = MsgPackFuture::new(reader)
+        .decode()
+        .await?
+        .into_array()
+        .filter(|a| a.len() == 3)
+        .ok_or_else(invalid)?;
+    let (direction, a) = a
+        .next()
+        .into_option()
+        .unwrap()
+        .decode()
+        .await?
+        .into_u64()
+        .ok_or_else(invalid)?;
+    let (offset, a) = a
+        .next()
+        .into_option()
+        .unwrap()
+        .decode()
+        .await?
+        .into_u64()
+        .ok_or_else(invalid)?;
+    let (message, r) = a
+        .last()
+        .into_option()
+        .unwrap()
+        .decode()
+        .await?
+        .into_bin()
+        .ok_or_else(invalid)?
+        .into_vec()
+        .await?;
+    let direction = match direction {
+        0 => Direction::Incoming,
+        1 => Direction::Outgoing,
+        _ => return Err(invalid()),
+    };
+    let entry = LogEntry {
+        direction,
+        offset: Duration::from_micros(offset),
+        message,
+    };
+    Ok((entry, r))
+}
+
+/// Whether `reader` has nothing more to read
+async fn at_eof<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> IoResult<bool> {
+    future::poll_fn(|cx| {
+        Pin::new(&mut *reader)
+            .poll_fill_buf(cx)
+            .map_ok(|buf| buf.is_empty())
+    })
+    .await
+}
+
+/// Truncate to the precision of the log format
+fn micros(d: Duration) -> Duration {
+    Duration::from_micros(u64::try_from(d.as_micros()).unwrap_or(u64::MAX))
+}
+
+fn decode_message(message: &[u8]) -> IoResult<Value> {
+    rmpv::decode::read_value(&mut &message[..])
+        .map_err(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Records every message of a session it's tracing
+#[derive(Clone)]
+pub struct SessionRecorder {
+    start: Instant,
+    entries: Rc<RefCell<Vec<LogEntry>>>,
+}
+
+impl Default for SessionRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionRecorder {
+    /// Start a recording, with offsets measured from now
+    pub fn new() -> Self {
+        SessionRecorder {
+            start: Instant::now(),
+            entries: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Trace to pass to `RpcSession::with_trace()`
+    pub fn trace(&self) -> Trace {
+        let recorder = self.clone();
+        Trace::new(move |e: &MessageEvent| {
+            recorder.entries.borrow_mut().push(LogEntry {
+                direction: e.direction,
+                offset: micros(e.start.saturating_duration_since(recorder.start)),
+                message: e.bytes.clone().unwrap(),
+            })
+        })
+        .with_hex_dump()
+    }
+
+    /// The messages recorded so far. Incoming messages are only recorded
+    /// once the following message starts, or the peer closes the connection.
+    pub fn recording(&self) -> Recording {
+        let mut entries = self.entries.borrow().clone();
+        // Events arrive as messages end, but are ordered by when they started
+        entries.sort_by_key(|e| e.offset);
+        Recording { entries }
+    }
+}
+
+/// Notes whether the replayed session was woken while it was being polled.
+/// If it wasn't, it's stalled.
+struct StallWaker {
+    woken: AtomicBool,
+    waker: Waker,
+}
+
+impl ArcWake for StallWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+        arc_self.waker.wake_by_ref();
+    }
+}
+
+#[derive(Default)]
+struct Gate {
+    /// Messages the server has sent so far
+    sent: Vec<Vec<u8>>,
+    /// Set while the reader is holding back the next message
+    waker: Option<Waker>,
+    /// Let the next message through regardless
+    forced: bool,
+}
+
+/// Plays the incoming side of a `Recording` to the server being replayed
+pub struct ReplayReader {
+    /// Incoming messages, with how many messages the server should have sent
+    /// before each
+    messages: VecDeque<(usize, Vec<u8>)>,
+    /// Position in the front message
+    pos: usize,
+    gate: Rc<RefCell<Gate>>,
+}
+
+impl AsyncRead for ReplayReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let this = &mut *self;
+        let (sent_before, message) = match this.messages.front() {
+            Some(front) => front,
+            None => return Poll::Ready(Ok(0)),
+        };
+        if this.pos == 0 {
+            let mut gate = this.gate.borrow_mut();
+            if gate.sent.len() < *sent_before && !gate.forced {
+                gate.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            gate.forced = false;
+            gate.waker = None;
+        }
+        let n = buf.len().min(message.len() - this.pos);
+        buf[..n].copy_from_slice(&message[this.pos..this.pos + n]);
+        this.pos += n;
+        if this.pos == message.len() {
+            this.messages.pop_front();
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Messages the server sent during a replay, and those it was expected to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub expected: Vec<Value>,
+    pub actual: Vec<Value>,
+}
+
+impl Replay {
+    pub fn is_match(&self) -> bool {
+        self.expected == self.actual
+    }
+
+    /// Positions where the sent messages differ from the recording
+    pub fn differences(&self) -> Vec<Difference> {
+        let len = self.expected.len().max(self.actual.len());
+        (0..len)
+            .filter_map(|index| {
+                let expected = self.expected.get(index);
+                let actual = self.actual.get(index);
+                if expected == actual {
+                    None
+                } else {
+                    Some(Difference {
+                        index,
+                        expected: expected.cloned(),
+                        actual: actual.cloned(),
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    /// Position among the outgoing messages
+    pub index: usize,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "message {}: expected ", self.index)?;
+        match &self.expected {
+            Some(v) => write!(f, "{}", v)?,
+            None => write!(f, "nothing")?,
+        }
+        write!(f, ", got ")?;
+        match &self.actual {
+            Some(v) => write!(f, "{}", v),
+            None => write!(f, "nothing"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::LocalPool;
+    use std::io::Cursor;
+
+    fn requests() -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (id, a) in [(1u32, 1u32), (2, 5)].iter() {
+            let req = Value::Array(vec![
+                0.into(),
+                (*id).into(),
+                "double".into(),
+                Value::Array(vec![(*a).into()]),
+            ]);
+            rmpv::encode::write_value(&mut buf, &req).unwrap();
+        }
+        buf
+    }
+
+    fn server<R>(factor: u64) -> RpcServer<R, Vec<u8>>
+    where
+        R: AsyncRead + Unpin + 'static,
+    {
+        let mut server = RpcServer::new();
+        server.add_method("double", move |params: Vec<Value>| {
+            future::ready(Ok((params[0].as_u64().unwrap() * factor).into()))
+        });
+        server
+    }
+
+    fn record() -> Recording {
+        let recorder = SessionRecorder::new();
+        let session = RpcSession::new(Cursor::new(requests()), Vec::new(), server(2))
+            .with_trace(recorder.trace());
+        LocalPool::new().run_until(session.run()).unwrap();
+        recorder.recording()
+    }
+
+    #[test]
+    fn write_and_read() {
+        let recording = record();
+        let directions: Vec<_> = recording.entries().iter().map(|e| e.direction).collect();
+        assert_eq!(directions.len(), 4);
+        assert_eq!(
+            directions
+                .iter()
+                .filter(|d| **d == Direction::Incoming)
+                .count(),
+            2
+        );
+
+        let buf = futures::executor::block_on(recording.write(Vec::new())).unwrap();
+        let read = futures::executor::block_on(Recording::read(Cursor::new(buf))).unwrap();
+        assert_eq!(read, recording);
+
+        let err =
+            futures::executor::block_on(Recording::read(Cursor::new(vec![0x90]))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replay() {
+        let recording = record();
+        let mut pool = LocalPool::new();
+        let replay = pool.run_until(recording.replay(server(2))).unwrap();
+        assert!(replay.is_match());
+        assert_eq!(replay.actual.len(), 2);
+
+        let replay = pool.run_until(recording.replay(server(3))).unwrap();
+        let differences = replay.differences();
+        assert_eq!(differences.len(), 2);
+        assert_eq!(
+            differences[0].to_string(),
+            "message 0: expected [1, 1, nil, 2], got [1, 1, nil, 3]"
+        );
+    }
+}
@@ -0,0 +1,748 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use rmpv::Value;
+
+use crate::decode::{ArrayFuture, StringFuture};
+use crate::rpc::decode::{RpcNotifyFuture, RpcParamsFuture, RpcRequestFuture};
+use crate::rpc::error::ApplicationError;
+use crate::rpc::middleware::{Interceptor, RequestInfo};
+use crate::rpc::shared::{RpcResponder, SharedWriter};
+use crate::rpc::subscription::{Subscription, Subscriptions};
+
+/// Built-in method that returns the names of all registered methods
+pub const LIST_METHODS: &str = "system.listMethods";
+
+/// Separates a namespace from the rest of a method name
+pub const NAMESPACE_SEPARATOR: char = '.';
+
+/// Work remaining for a message once its params have been read, such as
+/// producing and writing the response to a request
+pub type RpcTask = Pin<Box<dyn Future<Output = IoResult<()>>>>;
+
+/// Reads a message's params, then returns the reader along with the
+/// remaining work. The reader is handed back as early as possible so
+/// following messages can be read while the task runs.
+pub type HandlerFuture<R> = Pin<Box<dyn Future<Output = IoResult<(R, RpcTask)>>>>;
+
+pub trait RequestHandler<R, W> {
+    fn call(&self, params: ArrayFuture<R>, responder: RpcResponder<W>) -> HandlerFuture<R>;
+}
+
+impl<R, W, F> RequestHandler<R, W> for F
+where
+    F: Fn(ArrayFuture<R>, RpcResponder<W>) -> HandlerFuture<R>,
+{
+    fn call(&self, params: ArrayFuture<R>, responder: RpcResponder<W>) -> HandlerFuture<R> {
+        self(params, responder)
+    }
+}
+
+pub trait NotifyHandler<R> {
+    fn call(&self, params: ArrayFuture<R>) -> HandlerFuture<R>;
+}
+
+impl<R, F> NotifyHandler<R> for F
+where
+    F: Fn(ArrayFuture<R>) -> HandlerFuture<R>,
+{
+    fn call(&self, params: ArrayFuture<R>) -> HandlerFuture<R> {
+        self(params)
+    }
+}
+
+fn done() -> RpcTask {
+    future::ready(Ok(())).boxed_local()
+}
+
+/// Read the whole params array into a `Vec`
+pub(crate) async fn params_value<R>(params: ArrayFuture<R>) -> IoResult<(Vec<Value>, R)>
+where
+    R: AsyncRead + Unpin + 'static,
+{
+    let (params, r) = params.into_value().await?;
+    match params {
+        Value::Array(params) => Ok((params, r)),
+        _ => unreachable!(),
+    }
+}
+
+/// Routes incoming requests and notifications to handlers registered by
+/// method name.
+///
+/// Requests for unregistered methods get a `METHOD_NOT_FOUND` error
+/// response. Notifications without a handler are published to any
+/// `Subscription` streams for their method, or skipped if there are none.
+///
+/// Requests pass through any `Interceptor`s on the way to their handler.
+///
+/// Other servers can be mounted under a namespace, so that `fs.read` is
+/// dispatched as `read` by the server mounted as `fs`. A request to a
+/// mounted server passes through the interceptors of each server on the way,
+/// outermost first.
+pub struct RpcServer<R, W> {
+    methods: HashMap<String, Box<dyn RequestHandler<R, W>>>,
+    notifications: HashMap<String, Box<dyn NotifyHandler<R>>>,
+    interceptors: Vec<Box<dyn Interceptor<R>>>,
+    subscriptions: Subscriptions,
+    namespaces: HashMap<String, RpcServer<R, W>>,
+    /// Names longer than any registered method are skipped without reading
+    /// them into memory
+    max_method_len: usize,
+    max_namespace_len: usize,
+    not_found: ApplicationError,
+}
+
+/// Where a method name read by a server leads
+enum Route<'a, R, W, T> {
+    /// The rest of the name is for a mounted server. Some of it may have
+    /// been read already.
+    Mounted(&'a str, &'a RpcServer<R, W>, Vec<u8>, StringFuture<T>),
+    /// The whole name, to look up in this server
+    Local(String, T),
+    /// The name was too long to be registered, or wasn't UTF-8, and was
+    /// skipped
+    Skipped(T),
+}
+
+/// Namespaces and interceptors passed through on the way to a mounted
+/// server
+struct Scope<'a, R> {
+    prefix: String,
+    interceptors: Vec<&'a dyn Interceptor<R>>,
+}
+
+type DispatchFuture<'a, R> = Pin<Box<dyn Future<Output = IoResult<(R, RpcTask)>> + 'a>>;
+
+impl<R, W> Default for RpcServer<R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R, W> RpcServer<R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    pub fn new() -> Self {
+        RpcServer {
+            methods: HashMap::new(),
+            notifications: HashMap::new(),
+            interceptors: Vec::new(),
+            subscriptions: Subscriptions::new(),
+            namespaces: HashMap::new(),
+            max_method_len: LIST_METHODS.len(),
+            max_namespace_len: 0,
+            not_found: ApplicationError::method_not_found(),
+        }
+    }
+
+    pub fn add_handler(&mut self, method: &str, handler: impl RequestHandler<R, W> + 'static) {
+        self.max_method_len = self.max_method_len.max(method.len());
+        self.methods.insert(method.into(), Box::new(handler));
+    }
+
+    /// Register a method taking its params as dynamic `Value`s
+    pub fn add_method<F, Fut>(&mut self, method: &str, f: F)
+    where
+        F: Fn(Vec<Value>) -> Fut + 'static,
+        Fut: Future<Output = Result<Value, Value>> + 'static,
+    {
+        let f = Rc::new(f);
+        self.add_handler(method, move |params, responder: RpcResponder<W>| {
+            let f = f.clone();
+            async move {
+                let (params, r) = params_value(params).await?;
+                let result = f(params);
+                let task = async move {
+                    let result = result.await;
+                    responder.respond(result.as_ref()).await
+                };
+                Ok((r, task.boxed_local() as RpcTask))
+            }
+            .boxed_local()
+        });
+    }
+
+    pub fn add_notify_handler(&mut self, method: &str, handler: impl NotifyHandler<R> + 'static) {
+        self.max_method_len = self.max_method_len.max(method.len());
+        self.notifications.insert(method.into(), Box::new(handler));
+    }
+
+    /// Register a notification taking its params as dynamic `Value`s
+    pub fn add_notify<F, Fut>(&mut self, method: &str, f: F)
+    where
+        F: Fn(Vec<Value>) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let f = Rc::new(f);
+        self.add_notify_handler(method, move |params| {
+            let f = f.clone();
+            async move {
+                let (params, r) = params_value(params).await?;
+                let task = f(params).map(Ok);
+                Ok((r, task.boxed_local() as RpcTask))
+            }
+            .boxed_local()
+        });
+    }
+
+    /// Run `interceptor` on every request, inside any interceptors already
+    /// added
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor<R> + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Dispatch methods starting with `namespace` and `NAMESPACE_SEPARATOR`
+    /// to `server`, which can have namespaces of its own. Methods registered
+    /// directly under such names can no longer be called.
+    pub fn mount(&mut self, namespace: &str, server: RpcServer<R, W>) {
+        assert!(
+            !namespace.contains(NAMESPACE_SEPARATOR),
+            "namespace contains separator"
+        );
+        self.max_namespace_len = self.max_namespace_len.max(namespace.len());
+        self.namespaces.insert(namespace.into(), server);
+    }
+
+    /// Error sent for requests to methods this server doesn't have, in place
+    /// of the standard `METHOD_NOT_FOUND` error
+    pub fn set_method_not_found(&mut self, error: ApplicationError) {
+        self.not_found = error;
+    }
+
+    /// Stream of the params of each `method` notification that doesn't have a
+    /// handler
+    pub fn subscribe(&self, method: &str) -> Subscription {
+        self.subscriptions.subscribe(method)
+    }
+
+    pub fn subscriptions(&self) -> &Subscriptions {
+        &self.subscriptions
+    }
+
+    /// Set the capacity of subscriptions to this server and its mounted
+    /// servers
+    pub(crate) fn set_subscription_capacity(&self, capacity: Option<usize>) {
+        self.subscriptions.set_capacity(capacity);
+        for server in self.namespaces.values() {
+            server.set_subscription_capacity(capacity);
+        }
+    }
+
+    /// Names of request methods registered directly on this server, sorted
+    pub fn methods(&self) -> Vec<&str> {
+        let mut methods: Vec<&str> = self.methods.keys().map(String::as_str).collect();
+        methods.sort();
+        methods
+    }
+
+    /// Names of request methods, including those of mounted servers with
+    /// their namespaces, sorted
+    pub fn all_methods(&self) -> Vec<String> {
+        let mut methods: Vec<String> = self.methods.keys().cloned().collect();
+        for (namespace, server) in &self.namespaces {
+            methods.extend(
+                server
+                    .all_methods()
+                    .into_iter()
+                    .map(|m| format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, m)),
+            );
+        }
+        methods.sort();
+        methods
+    }
+
+    /// Work out where a method name leads, given the start of it that's
+    /// already been read and the rest. If it might be for a mounted server,
+    /// enough is read to hold any namespace and its separator. Names longer
+    /// than `max_len` that aren't for a mounted server are skipped.
+    async fn route<T: AsyncRead + Unpin>(
+        &self,
+        mut name: Vec<u8>,
+        mut rest: StringFuture<T>,
+        max_len: usize,
+    ) -> IoResult<Route<'_, R, W, T>> {
+        if !self.namespaces.is_empty() {
+            let want = self.max_namespace_len + 1;
+            if name.len() < want {
+                let (more, _, r) = rest
+                    .read_until(NAMESPACE_SEPARATOR as u8, want - name.len())
+                    .await?;
+                name.extend(more);
+                rest = r;
+            }
+            let found = name
+                .iter()
+                .take(want)
+                .position(|&b| b == NAMESPACE_SEPARATOR as u8);
+            if let Some(pos) = found {
+                let namespace = std::str::from_utf8(&name[..pos])
+                    .ok()
+                    .and_then(|namespace| self.namespaces.get_key_value(namespace));
+                if let Some((namespace, server)) = namespace {
+                    let tail = name.split_off(pos + 1);
+                    return Ok(Route::Mounted(namespace, server, tail, rest));
+                }
+            }
+        }
+        if name.len() + rest.len() > max_len {
+            return Ok(Route::Skipped(rest.skip().await?));
+        }
+        let start = name.len();
+        name.resize(start + rest.len(), 0);
+        let r = rest.read_all(&mut name[start..]).await?;
+        match String::from_utf8(name) {
+            Ok(name) => Ok(Route::Local(name, r)),
+            Err(_) => Ok(Route::Skipped(r)),
+        }
+    }
+
+    /// Read a request's method name and dispatch it to its handler. Returns
+    /// the reader positioned after the request and the task that writes the
+    /// response.
+    pub async fn handle_request(
+        &self,
+        req: RpcRequestFuture<R>,
+        writer: &SharedWriter<W>,
+    ) -> IoResult<(R, RpcTask)> {
+        let responder = req.responder(writer);
+        let id = req.id();
+        let method = req.method().await?;
+        let scope = Scope {
+            prefix: String::new(),
+            interceptors: Vec::new(),
+        };
+        self.dispatch_request(scope, id, Vec::new(), method, responder)
+            .await
+    }
+
+    fn dispatch_request<'a>(
+        &'a self,
+        mut scope: Scope<'a, R>,
+        id: u32,
+        read: Vec<u8>,
+        method: StringFuture<RpcParamsFuture<R>>,
+        responder: RpcResponder<W>,
+    ) -> DispatchFuture<'a, R> {
+        async move {
+            scope
+                .interceptors
+                .extend(self.interceptors.iter().map(|i| &**i));
+            let route = self.route(read, method, self.max_method_len).await?;
+            let (method, params) = match route {
+                Route::Mounted(namespace, server, read, rest) => {
+                    scope.prefix.push_str(namespace);
+                    scope.prefix.push(NAMESPACE_SEPARATOR);
+                    return server
+                        .dispatch_request(scope, id, read, rest, responder)
+                        .await;
+                }
+                // There's no name to show interceptors, so they're bypassed
+                Route::Skipped(params) => {
+                    let r = params.params().await?.skip().await?;
+                    return Ok((r, respond_error(responder, self.not_found.clone())));
+                }
+                Route::Local(method, params) => (method, params),
+            };
+            let params = params.params().await?;
+            let full_method = scope.prefix + &method;
+            let info = RequestInfo {
+                id,
+                method: &full_method,
+                num_params: params.len(),
+            };
+            for interceptor in &scope.interceptors {
+                if let Err(e) = interceptor.before(&info) {
+                    let r = params.skip().await?;
+                    return Ok((r, respond_error(responder, e)));
+                }
+            }
+            let handler = match self.methods.get(&method) {
+                Some(handler) => handler.call(params, responder),
+                None if method == LIST_METHODS => {
+                    let methods =
+                        Value::Array(self.all_methods().into_iter().map(Value::from).collect());
+                    async move {
+                        let r = params.skip().await?;
+                        let task = async move { responder.respond(Ok(&methods)).await };
+                        Ok((r, task.boxed_local() as RpcTask))
+                    }
+                    .boxed_local()
+                }
+                None => {
+                    let error = self.not_found.clone();
+                    async move {
+                        let r = params.skip().await?;
+                        Ok((r, respond_error(responder, error)))
+                    }
+                    .boxed_local()
+                }
+            };
+            let handler = scope
+                .interceptors
+                .iter()
+                .rev()
+                .fold(handler, |handler, interceptor| {
+                    interceptor.wrap(&info, handler)
+                });
+            handler.await
+        }
+        .boxed_local()
+    }
+
+    /// Read a notification's method name and dispatch it to its handler or
+    /// subscribers, or skip it if there are none
+    pub async fn handle_notify(&self, notify: RpcNotifyFuture<R>) -> IoResult<(R, RpcTask)> {
+        self.dispatch_notify(Vec::new(), notify.method().await?)
+            .await
+    }
+
+    fn dispatch_notify(
+        &self,
+        read: Vec<u8>,
+        method: StringFuture<RpcParamsFuture<R>>,
+    ) -> DispatchFuture<'_, R> {
+        async move {
+            let max_method_len = self.max_method_len.max(self.subscriptions.max_method_len());
+            let (method, params) = match self.route(read, method, max_method_len).await? {
+                Route::Mounted(_, server, read, rest) => {
+                    return server.dispatch_notify(read, rest).await
+                }
+                Route::Skipped(params) => {
+                    return Ok((params.params().await?.skip().await?, done()));
+                }
+                Route::Local(method, params) => (method, params),
+            };
+            let params = params.params().await?;
+            match self.notifications.get(&method) {
+                Some(handler) => handler.call(params).await,
+                None if self.subscriptions.is_subscribed(&method) => {
+                    let (params, r) = params_value(params).await?;
+                    // Waits for room in the subscriptions, pushing back on
+                    // the peer
+                    self.subscriptions.publish(&method, params).await;
+                    Ok((r, done()))
+                }
+                None => Ok((params.skip().await?, done())),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+fn respond_error<W>(responder: RpcResponder<W>, error: ApplicationError) -> RpcTask
+where
+    W: AsyncWrite + Unpin + 'static,
+{
+    async move { responder.respond_error(&error).await }.boxed_local()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::decode::{RpcMessage, RpcStream};
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    type Server = RpcServer<RpcStream<Cursor<Vec<u8>>>, Vec<u8>>;
+
+    /// Feed messages to the server one at a time, running each task to
+    /// completion, and return the decoded responses
+    fn serve(server: &Server, messages: &[Value]) -> Vec<Value> {
+        let mut buf = Vec::new();
+        for m in messages {
+            rmpv::encode::write_value(&mut buf, m).unwrap();
+        }
+        serve_bytes(server, buf, messages.len())
+    }
+
+    /// Like `serve()`, but with `count` messages already encoded in `buf`
+    fn serve_bytes(server: &Server, buf: Vec<u8>, count: usize) -> Vec<Value> {
+        let shared = SharedWriter::new(Vec::new());
+
+        async fn run(
+            server: &Server,
+            mut stream: RpcStream<Cursor<Vec<u8>>>,
+            shared: &SharedWriter<Vec<u8>>,
+            count: usize,
+        ) -> IoResult<()> {
+            for _ in 0..count {
+                let (r, task) = match stream.next().await? {
+                    RpcMessage::Request(req) => server.handle_request(req, shared).await?,
+                    RpcMessage::Notify(n) => server.handle_notify(n).await?,
+                    RpcMessage::Response(_) => panic!("Wrong message type"),
+                };
+                task.await?;
+                stream = r;
+            }
+            Ok(())
+        }
+        futures::executor::LocalPool::new()
+            .run_until(run(
+                server,
+                RpcStream::new(Cursor::new(buf)),
+                &shared,
+                count,
+            ))
+            .unwrap();
+
+        let out = shared.take();
+        let mut out = futures::executor::LocalPool::new().run_until(out).unwrap();
+        let mut out = Cursor::new(std::mem::take(out.get_mut()));
+        let mut values = Vec::new();
+        while (out.position() as usize) < out.get_ref().len() {
+            values.push(rmpv::decode::read_value(&mut out).unwrap());
+        }
+        values
+    }
+
+    fn request(id: u32, method: &str, params: Vec<Value>) -> Value {
+        Value::Array(vec![0.into(), id.into(), method.into(), params.into()])
+    }
+
+    fn response(id: u32, error: Value, result: Value) -> Value {
+        Value::Array(vec![1.into(), id.into(), error, result])
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut server = Server::new();
+        server.add_method("add", |params| {
+            let sum = params.iter().map(|p| p.as_u64().unwrap()).sum::<u64>();
+            future::ready(Ok(sum.into()))
+        });
+        server.add_method("fail", |_| future::ready(Err("failed".into())));
+        let out = serve(
+            &server,
+            &[
+                request(1, "add", vec![1.into(), 2.into()]),
+                request(2, "fail", vec![]),
+                request(3, "missing", vec![1.into()]),
+                request(4, "a method name longer than anything registered", vec![]),
+                request(5, LIST_METHODS, vec![]),
+            ],
+        );
+        assert_eq!(
+            out,
+            vec![
+                response(1, Value::Nil, 3.into()),
+                response(2, "failed".into(), Value::Nil),
+                response(
+                    3,
+                    ApplicationError::method_not_found().to_value(),
+                    Value::Nil
+                ),
+                response(
+                    4,
+                    ApplicationError::method_not_found().to_value(),
+                    Value::Nil
+                ),
+                response(
+                    5,
+                    Value::Nil,
+                    Value::Array(vec!["add".into(), "fail".into()])
+                ),
+            ]
+        );
+
+        // A method name that isn't UTF-8 isn't found either
+        let invalid = vec![0x94, 0, 6, 0xa2, 0xff, 0xfe, 0x90];
+        assert_eq!(
+            serve_bytes(&server, invalid, 1),
+            vec![response(
+                6,
+                ApplicationError::method_not_found().to_value(),
+                Value::Nil
+            )]
+        );
+    }
+
+    #[test]
+    fn notify() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_notify = seen.clone();
+        let mut server = Server::new();
+        server.add_notify("redraw", move |params| {
+            seen_notify.borrow_mut().push(params);
+            future::ready(())
+        });
+
+        let out = serve(
+            &server,
+            &[
+                Value::Array(vec![
+                    2.into(),
+                    "ignored".into(),
+                    vec![Value::from(1)].into(),
+                ]),
+                Value::Array(vec![
+                    2.into(),
+                    "redraw".into(),
+                    vec![Value::from("flush")].into(),
+                ]),
+            ],
+        );
+        assert!(out.is_empty());
+        assert_eq!(*seen.borrow(), vec![vec![Value::from("flush")]]);
+    }
+
+    #[test]
+    fn interceptors() {
+        use crate::rpc::middleware::MaxParams;
+        use std::cell::Cell;
+
+        /// Rejects everything until the handshake method is called
+        struct Auth(Rc<Cell<bool>>);
+
+        impl<R> Interceptor<R> for Auth {
+            fn before(&self, req: &RequestInfo) -> Result<(), ApplicationError> {
+                if self.0.get() || req.method == "hello" {
+                    Ok(())
+                } else {
+                    Err(ApplicationError::new(1, "not authenticated"))
+                }
+            }
+        }
+
+        /// Records the methods whose responses were written
+        struct Log(Rc<RefCell<Vec<String>>>);
+
+        impl<R: 'static> Interceptor<R> for Log {
+            fn wrap(&self, req: &RequestInfo, handler: HandlerFuture<R>) -> HandlerFuture<R> {
+                let log = self.0.clone();
+                let method = req.method.to_string();
+                handler
+                    .map_ok(move |(r, task)| {
+                        let task = task.inspect(move |_| log.borrow_mut().push(method));
+                        (r, task.boxed_local() as RpcTask)
+                    })
+                    .boxed_local()
+            }
+        }
+
+        let authed = Rc::new(Cell::new(false));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut server = Server::new();
+        server.add_interceptor(Auth(authed.clone()));
+        server.add_interceptor(MaxParams(2));
+        server.add_interceptor(Log(log.clone()));
+        server.add_method("hello", move |_| {
+            authed.set(true);
+            future::ready(Ok(Value::Nil))
+        });
+        server.add_method("add", |params| {
+            let sum = params.iter().map(|p| p.as_u64().unwrap()).sum::<u64>();
+            future::ready(Ok(sum.into()))
+        });
+
+        let out = serve(
+            &server,
+            &[
+                request(1, "add", vec![1.into(), 2.into()]),
+                request(2, "hello", vec![]),
+                request(3, "add", vec![1.into(), 2.into(), 3.into()]),
+                request(4, "add", vec![1.into(), 2.into()]),
+                request(5, "missing", vec![]),
+            ],
+        );
+        let not_authed = ApplicationError::new(1, "not authenticated");
+        let too_many = ApplicationError::invalid_params("too many params: 3 > 2");
+        assert_eq!(
+            out,
+            vec![
+                response(1, not_authed.to_value(), Value::Nil),
+                response(2, Value::Nil, Value::Nil),
+                response(3, too_many.to_value(), Value::Nil),
+                response(4, Value::Nil, 3.into()),
+                response(
+                    5,
+                    ApplicationError::method_not_found().to_value(),
+                    Value::Nil
+                ),
+            ]
+        );
+        assert_eq!(*log.borrow(), vec!["hello", "add", "missing"]);
+    }
+
+    #[test]
+    fn namespaces() {
+        use crate::rpc::error::METHOD_NOT_FOUND;
+        use crate::rpc::middleware::MaxParams;
+
+        let mut lines = Server::new();
+        lines.add_method("get", |_| future::ready(Ok("line".into())));
+        let mut buf = Server::new();
+        buf.add_method("name", |_| future::ready(Ok("main.rs".into())));
+        buf.mount("lines", lines);
+        buf.add_interceptor(MaxParams(0));
+        let no_such_buf = ApplicationError::new(METHOD_NOT_FOUND, "no such buf method");
+        buf.set_method_not_found(no_such_buf.clone());
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_notify = seen.clone();
+        let mut fs = Server::new();
+        fs.add_notify("changed", move |params| {
+            seen_notify.borrow_mut().push(params);
+            future::ready(())
+        });
+
+        let mut server = Server::new();
+        server.add_method("version", |_| future::ready(Ok(1.into())));
+        server.mount("buf", buf);
+        server.mount("fs", fs);
+
+        let out = serve(
+            &server,
+            &[
+                request(1, "buf.name", vec![]),
+                request(2, "buf.lines.get", vec![]),
+                request(3, "buf.lines.get", vec![1.into()]),
+                request(4, "buf.missing", vec![]),
+                request(5, "version", vec![]),
+                request(6, "bu", vec![]),
+                Value::Array(vec![
+                    2.into(),
+                    "fs.changed".into(),
+                    vec![Value::from("a.txt")].into(),
+                ]),
+                request(7, LIST_METHODS, vec![]),
+            ],
+        );
+        let too_many = ApplicationError::invalid_params("too many params: 1 > 0");
+        assert_eq!(
+            out,
+            vec![
+                response(1, Value::Nil, "main.rs".into()),
+                response(2, Value::Nil, "line".into()),
+                // Middleware of the outer namespace applies to inner ones
+                response(3, too_many.to_value(), Value::Nil),
+                response(4, no_such_buf.to_value(), Value::Nil),
+                response(5, Value::Nil, 1.into()),
+                response(
+                    6,
+                    ApplicationError::method_not_found().to_value(),
+                    Value::Nil
+                ),
+                response(
+                    7,
+                    Value::Nil,
+                    Value::Array(vec![
+                        "buf.lines.get".into(),
+                        "buf.name".into(),
+                        "version".into(),
+                    ])
+                ),
+            ]
+        );
+        assert_eq!(*seen.borrow(), vec![vec![Value::from("a.txt")]]);
+    }
+}
@@ -0,0 +1,67 @@
+//! Building blocks for typed services, used by the code generated by
+//! `rmp-futures-derive`. Params and results are encoded with `ToMsgPack` and
+//! decoded with `FromMsgPack` and `ArrayFuture::extract()`.
+
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+
+use crate::decode::ExtractError;
+use crate::encode::ToMsgPack;
+use crate::rpc::client::{RpcCall, TypedResult};
+use crate::rpc::encode::RpcParamsSink;
+use crate::rpc::error::ApplicationError;
+use crate::rpc::server::RpcTask;
+use crate::rpc::shared::RpcResponder;
+
+// Re-exported for bounds in generated code
+pub use futures::io::{AsyncRead, AsyncWrite};
+
+/// Task that responds with an `INVALID_PARAMS` error describing why the
+/// params couldn't be extracted
+pub fn invalid_params<W>(responder: RpcResponder<W>, error: ExtractError) -> RpcTask
+where
+    W: AsyncWrite + Unpin + 'static,
+{
+    let error = ApplicationError::invalid_params(error.to_string());
+    async move { responder.respond_error(&error).await }.boxed_local()
+}
+
+/// Task that waits for a method's result and responds with it
+pub fn respond<W, T, E>(
+    responder: RpcResponder<W>,
+    result: impl Future<Output = Result<T, E>> + 'static,
+) -> RpcTask
+where
+    W: AsyncWrite + Unpin + 'static,
+    T: ToMsgPack,
+    E: ToMsgPack,
+{
+    async move {
+        match result.await {
+            Ok(val) => {
+                let w = val.to_msgpack(responder.respond_ok().await?).await?;
+                w.release().await
+            }
+            Err(e) => {
+                let w = e.to_msgpack(responder.respond_err().await?).await?;
+                w.finish().await?.release().await
+            }
+        }
+    }
+    .boxed_local()
+}
+
+/// Write the next param of a call
+pub async fn write_param<T, W>(params: RpcParamsSink<W>, val: &T) -> IoResult<RpcParamsSink<W>>
+where
+    T: ToMsgPack + ?Sized,
+    W: AsyncWrite + Unpin,
+{
+    val.to_msgpack(params.next().unwrap()).await
+}
+
+/// Wait for the response to a typed call, folding a failure to read it into
+/// the `RpcError`
+pub async fn call_result<T, E>(call: RpcCall<TypedResult<T, E>>) -> TypedResult<T, E> {
+    call.await.unwrap_or_else(|e| Err(e.into()))
+}
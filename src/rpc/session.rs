@@ -0,0 +1,491 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::io::{ReadHalf, Result as IoResult, WriteHalf};
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+
+use crate::rpc::client::RpcClient;
+use crate::rpc::decode::{RpcMessage, RpcStream};
+use crate::rpc::limit::Limits;
+use crate::rpc::observe::Trace;
+use crate::rpc::server::{RpcServer, RpcTask};
+use crate::rpc::shared::SharedWriter;
+use crate::rpc::subscription::Subscription;
+
+/// Both ends of a single msgpack-rpc connection. Outgoing calls are made
+/// through `client()`, while `run()` reads incoming messages, routing
+/// responses to those calls and dispatching requests and notifications to
+/// the server's handlers.
+pub struct RpcSession<R, W> {
+    stream: RpcStream<R>,
+    writer: SharedWriter<W>,
+    client: RpcClient<W>,
+    server: Rc<RpcServer<RpcStream<R>, W>>,
+    max_handlers: Option<usize>,
+    shutdown: Shutdown,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    requested: bool,
+    waker: Option<Waker>,
+}
+
+/// Handle for asking a running `RpcSession` to shut down
+#[derive(Clone, Default)]
+pub struct Shutdown(Rc<RefCell<ShutdownState>>);
+
+impl Shutdown {
+    /// Stop reading new messages and let `run()` drain. Any message partway
+    /// through being read is dropped.
+    pub fn shutdown(&self) {
+        let mut state = self.0.borrow_mut();
+        state.requested = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.borrow().requested
+    }
+
+    fn poll_requested(&self, cx: &mut Context) -> Poll<()> {
+        let mut state = self.0.borrow_mut();
+        if state.requested {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> RpcSession<ReadHalf<T>, WriteHalf<T>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    /// Create a session over a transport that is both readable and writable
+    pub fn from_duplex(io: T, server: RpcServer<RpcStream<ReadHalf<T>>, WriteHalf<T>>) -> Self {
+        let (reader, writer) = io.split();
+        Self::new(reader, writer, server)
+    }
+}
+
+impl<R, W> RpcSession<R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    pub fn new(reader: R, writer: W, server: RpcServer<RpcStream<R>, W>) -> Self {
+        Self::with_limits(reader, writer, server, Limits::default())
+    }
+
+    pub fn with_limits(
+        reader: R,
+        writer: W,
+        server: RpcServer<RpcStream<R>, W>,
+        limits: Limits,
+    ) -> Self {
+        let writer = match limits.max_queued_writes {
+            Some(max) => SharedWriter::with_queue_limit(writer, max),
+            None => SharedWriter::new(writer),
+        };
+        server.set_subscription_capacity(limits.max_queued_notifications);
+        let mut client = RpcClient::new(writer.clone());
+        if let Some(max) = limits.max_pending_calls {
+            client = client.with_max_pending(max);
+        }
+        RpcSession {
+            stream: RpcStream::new(reader),
+            client,
+            writer,
+            server: Rc::new(server),
+            max_handlers: limits.max_handlers,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    /// Report every message sent and received to `trace`
+    pub fn with_trace(mut self, trace: Trace) -> Self {
+        self.stream.set_trace(trace.clone());
+        self.writer.clone().with_trace(trace);
+        self
+    }
+
+    /// Handle for shutting down the session once it's running
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Client for making calls to the peer. Its calls only complete while
+    /// `run()` is reading responses.
+    pub fn client(&self) -> RpcClient<W> {
+        self.client.clone()
+    }
+
+    /// Stream of the params of each `method` notification from the peer that
+    /// doesn't have a handler. Ends when the session does.
+    ///
+    /// With `Limits::max_queued_notifications`, reading stops while the
+    /// stream is full, so keep reading it or drop it.
+    pub fn subscribe(&self, method: &str) -> Subscription {
+        self.server.subscribe(method)
+    }
+
+    /// Read and dispatch incoming messages until the peer closes the
+    /// connection or a shutdown is requested. Handler tasks run concurrently
+    /// with reading, so handlers may themselves make calls to the peer.
+    ///
+    /// Once reading stops, pending calls to the peer fail with
+    /// `ErrorKind::NotConnected`, the handlers still running are left to
+    /// finish and their responses are flushed before returning. If the
+    /// connection fails, including EOF partway through a message, the error
+    /// is returned straight away.
+    pub async fn run(self) -> IoResult<()> {
+        let RpcSession {
+            stream,
+            writer,
+            client,
+            server,
+            max_handlers,
+            shutdown,
+        } = self;
+        let mut tasks = FuturesUnordered::<RpcTask>::new();
+        let abandoned = writer.clone();
+        tasks.push(
+            async move {
+                loop {
+                    abandoned.respond_abandoned().await?;
+                }
+            }
+            .boxed_local(),
+        );
+
+        let read_next =
+            |stream| next_message(stream, server.clone(), client.clone(), writer.clone());
+        // Reading is paused, leaving the stream here, while the handler limit
+        // is reached
+        let mut paused = None;
+        let mut read = Some(read_next(stream));
+        let mut draining = false;
+        let result = future::poll_fn(|cx| loop {
+            while let Poll::Ready(Some(result)) = tasks.poll_next_unpin(cx) {
+                result?;
+            }
+            if draining {
+                // Only the task responding to abandoned requests is left
+                if tasks.len() == 1 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Pending;
+            }
+            if shutdown.poll_requested(cx).is_ready() {
+                read = None;
+                paused = None;
+                draining = true;
+                client.close();
+                continue;
+            }
+            if read.is_none() {
+                // Not counting the task responding to abandoned requests
+                let running = tasks.len() - 1;
+                if max_handlers.is_none_or(|max| running < max) {
+                    read = paused.take().map(read_next);
+                } else {
+                    return Poll::Pending;
+                }
+            }
+            match read.as_mut().unwrap().as_mut().poll(cx) {
+                Poll::Ready(Ok(Some((stream, task)))) => {
+                    tasks.push(task);
+                    paused = Some(stream);
+                    read = None;
+                }
+                Poll::Ready(Ok(None)) => {
+                    read = None;
+                    draining = true;
+                    client.close();
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        })
+        .await;
+        // No more responses will arrive, however reading ended
+        client.close();
+        result?;
+        // Write responses for requests abandoned while draining, and flush
+        writer.take().await?.release().await
+    }
+}
+
+type ReadFuture<R> = Pin<Box<dyn Future<Output = IoResult<Option<(RpcStream<R>, RpcTask)>>>>>;
+
+/// Read one message and hand it to the client or server, or return `None` if
+/// the peer closed the connection
+fn next_message<R, W>(
+    stream: RpcStream<R>,
+    server: Rc<RpcServer<RpcStream<R>, W>>,
+    client: RpcClient<W>,
+    writer: SharedWriter<W>,
+) -> ReadFuture<R>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    async move {
+        let handled = match stream.try_next().await? {
+            Some(RpcMessage::Request(req)) => server.handle_request(req, &writer).await?,
+            Some(RpcMessage::Notify(notify)) => server.handle_notify(notify).await?,
+            Some(RpcMessage::Response(resp)) => {
+                let stream = client.handle_response(resp).await?;
+                (stream, future::ready(Ok(())).boxed_local() as RpcTask)
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(handled))
+    }
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::io::ErrorKind;
+    use rmpv::Value;
+    use std::io::Cursor;
+
+    #[test]
+    fn call_and_serve() {
+        let incoming = [
+            Value::Array(vec![
+                0.into(),
+                7.into(),
+                "add".into(),
+                Value::Array(vec![1.into(), 2.into()]),
+            ]),
+            Value::Array(vec![1.into(), 0.into(), Value::Nil, "pong".into()]),
+        ];
+        let mut buf = Vec::new();
+        for m in &incoming {
+            rmpv::encode::write_value(&mut buf, m).unwrap();
+        }
+
+        let mut server = RpcServer::new();
+        server.add_method("add", |params: Vec<Value>| {
+            let sum = params.iter().map(|p| p.as_u64().unwrap()).sum::<u64>();
+            future::ready(Ok(sum.into()))
+        });
+        let session = RpcSession::new(Cursor::new(buf), Vec::new(), server);
+        let client = session.client();
+        let writer = session.writer.clone();
+
+        let (call, run) = futures::executor::LocalPool::new()
+            .run_until(future::join(client.call_value("ping", &[]), session.run()));
+        assert_eq!(call.unwrap(), Ok("pong".into()));
+        // The script runs out, which closes the session cleanly
+        run.unwrap();
+
+        let mut expected = Vec::new();
+        let out = [
+            Value::Array(vec![
+                0.into(),
+                0.into(),
+                "ping".into(),
+                Value::Array(vec![]),
+            ]),
+            Value::Array(vec![1.into(), 7.into(), Value::Nil, 3.into()]),
+        ];
+        for m in &out {
+            rmpv::encode::write_value(&mut expected, m).unwrap();
+        }
+        let mut guard = futures::executor::block_on(writer.take()).unwrap();
+        assert_eq!(*guard.get_mut(), expected);
+    }
+
+    #[test]
+    fn subscribe() {
+        let incoming = [
+            Value::Array(vec![2.into(), "redraw".into(), vec![Value::from(1)].into()]),
+            Value::Array(vec![2.into(), "other".into(), vec![Value::from(2)].into()]),
+            Value::Array(vec![2.into(), "redraw".into(), vec![Value::from(3)].into()]),
+        ];
+        let mut buf = Vec::new();
+        for m in &incoming {
+            rmpv::encode::write_value(&mut buf, m).unwrap();
+        }
+
+        let session = RpcSession::new(Cursor::new(buf), Vec::new(), RpcServer::new());
+        let redraw = session.subscribe("redraw");
+        let (redraw, run) = futures::executor::LocalPool::new()
+            .run_until(future::join(redraw.collect::<Vec<_>>(), session.run()));
+        run.unwrap();
+        assert_eq!(redraw, vec![vec![Value::from(1)], vec![Value::from(3)]]);
+    }
+
+    #[test]
+    fn max_handlers() {
+        let mut buf = Vec::new();
+        for id in 0..3u32 {
+            let req = Value::Array(vec![
+                0.into(),
+                id.into(),
+                "work".into(),
+                Value::Array(vec![]),
+            ]);
+            rmpv::encode::write_value(&mut buf, &req).unwrap();
+        }
+
+        // Number of handlers running now, and the most ever seen at once
+        let running = Rc::new(std::cell::Cell::new((0, 0)));
+        let mut server = RpcServer::new();
+        let handler_running = running.clone();
+        server.add_method("work", move |_| {
+            let running = handler_running.clone();
+            async move {
+                let (now, most) = running.get();
+                running.set((now + 1, most.max(now + 1)));
+                // Give other handlers a chance to start
+                for _ in 0..3 {
+                    let mut yielded = false;
+                    future::poll_fn(|cx| {
+                        if yielded {
+                            Poll::Ready(())
+                        } else {
+                            yielded = true;
+                            cx.waker().wake_by_ref();
+                            Poll::Pending
+                        }
+                    })
+                    .await;
+                }
+                let (now, most) = running.get();
+                running.set((now - 1, most));
+                Ok(Value::Nil)
+            }
+        });
+        let limits = Limits {
+            max_handlers: Some(1),
+            ..Limits::default()
+        };
+        let session = RpcSession::with_limits(Cursor::new(buf), Vec::new(), server, limits);
+        let writer = session.writer.clone();
+        let run = futures::executor::LocalPool::new().run_until(session.run());
+        run.unwrap();
+        assert_eq!(running.get(), (0, 1));
+
+        let mut guard = futures::executor::block_on(writer.take()).unwrap();
+        let mut out = Cursor::new(std::mem::take(guard.get_mut()));
+        for id in 0..3u32 {
+            let resp = rmpv::decode::read_value(&mut out).unwrap();
+            assert_eq!(
+                resp,
+                Value::Array(vec![1.into(), id.into(), Value::Nil, Value::Nil])
+            );
+        }
+    }
+
+    /// Reader that plays back a script and then waits forever, like a peer
+    /// that stays connected
+    struct Script(Cursor<Vec<u8>>);
+
+    impl AsyncRead for Script {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<IoResult<usize>> {
+            match Pin::new(&mut self.0).poll_read(cx, buf) {
+                Poll::Ready(Ok(0)) => Poll::Pending,
+                poll => poll,
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown() {
+        let req = Value::Array(vec![
+            0.into(),
+            7.into(),
+            "slow".into(),
+            Value::Array(vec![]),
+        ]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &req).unwrap();
+
+        let started = Rc::new(std::cell::Cell::new(false));
+        let handler_started = started.clone();
+        let mut server = RpcServer::new();
+        server.add_method("slow", move |_| {
+            handler_started.set(true);
+            async {
+                for _ in 0..3 {
+                    let mut yielded = false;
+                    future::poll_fn(|cx| {
+                        if yielded {
+                            Poll::Ready(())
+                        } else {
+                            yielded = true;
+                            cx.waker().wake_by_ref();
+                            Poll::Pending
+                        }
+                    })
+                    .await;
+                }
+                Ok("done".into())
+            }
+        });
+        let session = RpcSession::new(Script(Cursor::new(buf)), Vec::new(), server);
+        let client = session.client();
+        let writer = session.writer.clone();
+        let shutdown = session.shutdown_handle();
+        let stop = future::poll_fn(|cx| {
+            if started.get() {
+                shutdown.shutdown();
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        });
+
+        let (run, call, ()) = futures::executor::LocalPool::new().run_until(future::join3(
+            session.run(),
+            client.call_value("ping", &[]),
+            stop,
+        ));
+        run.unwrap();
+        assert_eq!(call.unwrap_err().kind(), ErrorKind::NotConnected);
+
+        // The handler still got to respond
+        let mut guard = futures::executor::block_on(writer.take()).unwrap();
+        let mut out = Cursor::new(std::mem::take(guard.get_mut()));
+        let written = [
+            rmpv::decode::read_value(&mut out).unwrap(),
+            rmpv::decode::read_value(&mut out).unwrap(),
+        ];
+        let resp = Value::Array(vec![1.into(), 7.into(), Value::Nil, "done".into()]);
+        assert!(written.contains(&resp));
+    }
+
+    #[test]
+    fn truncated() {
+        let req = Value::Array(vec![
+            0.into(),
+            7.into(),
+            "add".into(),
+            Value::Array(vec![1.into(), 2.into()]),
+        ]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &req).unwrap();
+        buf.pop();
+
+        let session = RpcSession::new(Cursor::new(buf), Vec::new(), RpcServer::new());
+        let run = futures::executor::LocalPool::new().run_until(session.run());
+        assert_eq!(run.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,888 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use rmpv::Value;
+
+use crate::encode::{MsgPackSink, ToMsgPack};
+use crate::rpc::encode::{
+    RpcNotifySink, RpcParamsSink, RpcRequestSink, RpcResponseSink, RpcResultSink,
+};
+use crate::rpc::error::ApplicationError;
+use crate::rpc::observe::{Direction, MessageType, Recorder, Trace};
+
+/// Error message sent for requests whose responder was dropped without
+/// replying
+const ABANDONED_ERROR: &str = "request dropped without a response";
+
+/// Ownership state of a writer shared between multiple outgoing messages.
+/// Whoever holds the writer is in the middle of writing a message. Everyone
+/// else waits in line, in the order they asked for it. If the line is
+/// limited to `max_queued`, latecomers wait their turn to join it.
+struct WriterSlot<W> {
+    writer: Option<W>,
+    waiters: VecDeque<(usize, Waker)>,
+    /// Waiting to join `waiters` once there's room
+    overflow: VecDeque<(usize, Waker)>,
+    next_key: usize,
+    max_queued: Option<usize>,
+    /// Requests that need an error response written on their behalf
+    abandoned: VecDeque<u32>,
+    abandoned_waker: Option<Waker>,
+    /// A message was left unfinished, so nothing more can be written
+    poisoned: bool,
+}
+
+impl<W> WriterSlot<W> {
+    fn new(writer: W, max_queued: Option<usize>) -> Self {
+        WriterSlot {
+            writer: Some(writer),
+            waiters: VecDeque::with_capacity(max_queued.unwrap_or(0)),
+            overflow: VecDeque::new(),
+            next_key: 0,
+            max_queued,
+            abandoned: VecDeque::new(),
+            abandoned_waker: None,
+            poisoned: false,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_queued.is_some_and(|max| self.waiters.len() >= max)
+    }
+
+    /// Whoever's turn it is to take the writer next. That's normally the
+    /// front of the line, unless the line has no room at all.
+    fn front(&self) -> Option<&(usize, Waker)> {
+        self.waiters.front().or_else(|| self.overflow.front())
+    }
+
+    /// Move waiters from the overflow into the line while there's room
+    fn admit(&mut self) {
+        while !self.is_full() {
+            match self.overflow.pop_front() {
+                Some(waiter) => self.waiters.push_back(waiter),
+                None => break,
+            }
+        }
+    }
+
+    /// Take the writer if it's available and it's our turn, otherwise get in
+    /// line. `key` tracks our place in line between polls.
+    fn poll_take(&mut self, key: &mut Option<usize>, cx: &mut Context) -> Poll<IoResult<W>> {
+        if self.poisoned {
+            *key = None;
+            return Poll::Ready(Err(IoError::new(
+                ErrorKind::BrokenPipe,
+                "shared writer was left partway through a message",
+            )));
+        }
+        match *key {
+            None => {
+                if self.front().is_none() {
+                    if let Some(w) = self.writer.take() {
+                        return Poll::Ready(Ok(w));
+                    }
+                }
+                let k = self.next_key;
+                self.next_key = self.next_key.wrapping_add(1);
+                if self.is_full() {
+                    self.overflow.push_back((k, cx.waker().clone()));
+                } else {
+                    self.waiters.push_back((k, cx.waker().clone()));
+                }
+                *key = Some(k);
+                Poll::Pending
+            }
+            Some(k) => {
+                if self.front().map(|(front, _)| *front) == Some(k) {
+                    if let Some(w) = self.writer.take() {
+                        if self.waiters.pop_front().is_none() {
+                            self.overflow.pop_front();
+                        }
+                        self.admit();
+                        *key = None;
+                        return Poll::Ready(Ok(w));
+                    }
+                }
+                let waiter = self
+                    .waiters
+                    .iter_mut()
+                    .chain(self.overflow.iter_mut())
+                    .find(|(w, _)| *w == k);
+                if let Some((_, waker)) = waiter {
+                    if !waker.will_wake(cx.waker()) {
+                        *waker = cx.waker().clone();
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Leave the line without taking the writer
+    fn cancel(&mut self, key: usize) {
+        self.waiters.retain(|(k, _)| *k != key);
+        self.overflow.retain(|(k, _)| *k != key);
+        self.admit();
+        self.wake_next();
+    }
+
+    /// Drop the writer of an unfinished message, and fail everyone waiting
+    /// for it
+    fn poison(&mut self) {
+        self.poisoned = true;
+        for (_, waker) in self.waiters.drain(..).chain(self.overflow.drain(..)) {
+            waker.wake();
+        }
+    }
+
+    fn give_back(&mut self, writer: W) {
+        self.writer = Some(writer);
+        self.wake_next();
+    }
+
+    fn abandon(&mut self, id: u32) {
+        self.abandoned.push_back(id);
+        if let Some(waker) = self.abandoned_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_abandoned(&mut self, cx: &mut Context) -> Poll<()> {
+        if self.abandoned.is_empty() {
+            self.abandoned_waker = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    fn wake_next(&self) {
+        if self.writer.is_some() {
+            if let Some((_, waker)) = self.front() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Writer shared by any number of outgoing messages on a single-threaded
+/// executor.
+///
+/// Each message takes exclusive ownership of the writer while it is encoded
+/// directly to it, then hands it back to the next message in line when the
+/// guard is dropped. This allows concurrent tasks to stream requests,
+/// responses and notifications as the writer becomes writable without
+/// allocating the messages up front.
+pub struct SharedWriter<W> {
+    slot: Rc<RefCell<WriterSlot<W>>>,
+    /// Kept out of the slot, which `SyncSharedWriter` shares between threads
+    recorder: Rc<RefCell<Option<Recorder>>>,
+}
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        SharedWriter {
+            slot: self.slot.clone(),
+            recorder: self.recorder.clone(),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SharedWriter<W> {
+    /// Share a writer with no limit on the number of queued messages
+    pub fn new(writer: W) -> Self {
+        Self::with_slot(WriterSlot::new(writer, None))
+    }
+
+    /// Share a writer, letting at most `max_queued` messages wait in line
+    /// for it. Once the line is full, `take()` waits for room in it.
+    pub fn with_queue_limit(writer: W, max_queued: usize) -> Self {
+        Self::with_slot(WriterSlot::new(writer, Some(max_queued)))
+    }
+
+    fn with_slot(slot: WriterSlot<W>) -> Self {
+        SharedWriter {
+            slot: Rc::new(RefCell::new(slot)),
+            recorder: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// Report each message written to `trace`. Each time the writer is
+    /// taken counts as one message.
+    pub fn with_trace(self, trace: Trace) -> Self {
+        *self.recorder.borrow_mut() = Some(Recorder::new(trace, Direction::Outgoing));
+        self
+    }
+
+    /// Number of messages in line for the writer, not counting any waiting
+    /// for room in the line
+    pub fn queued(&self) -> usize {
+        self.slot.borrow().waiters.len()
+    }
+
+    /// Wait for exclusive ownership of the writer. Error responses for any
+    /// abandoned requests are written before the writer is returned.
+    pub async fn take(&self) -> IoResult<SharedWriterGuard<W>> {
+        let mut guard = TakeWriter {
+            shared: self.clone(),
+            key: None,
+        }
+        .await?;
+        guard.write_abandoned().await?;
+        guard.trace(Recorder::begin);
+        Ok(guard)
+    }
+
+    /// Wait for a request to be abandoned, then write its error response.
+    /// Drive this alongside the reader if other messages might not be written
+    /// promptly enough to carry abandoned responses along with them.
+    pub async fn respond_abandoned(&self) -> IoResult<()> {
+        future::poll_fn(|cx| self.slot.borrow_mut().poll_abandoned(cx)).await;
+        self.take().await?.release().await
+    }
+
+    /// Responder for a request received with the given id
+    pub fn responder(&self, id: u32) -> RpcResponder<W> {
+        RpcResponder {
+            writer: Some(self.clone()),
+            id,
+        }
+    }
+
+    pub async fn request(
+        &self,
+        id: u32,
+        method: &str,
+        num_params: u32,
+    ) -> IoResult<RpcParamsSink<SharedWriterGuard<W>>> {
+        let w = self.take().await?;
+        w.trace(|r| {
+            r.describe(MessageType::Request, Some(id));
+            r.set_method(method);
+        });
+        RpcRequestSink::new(w, id).method(method, num_params).await
+    }
+
+    pub async fn notify(
+        &self,
+        method: &str,
+        num_params: u32,
+    ) -> IoResult<RpcParamsSink<SharedWriterGuard<W>>> {
+        let w = self.take().await?;
+        w.trace(|r| {
+            r.describe(MessageType::Notify, None);
+            r.set_method(method);
+        });
+        RpcNotifySink::new(w).method(method, num_params).await
+    }
+
+    pub async fn response(&self, id: u32) -> IoResult<RpcResponseSink<SharedWriterGuard<W>>> {
+        let w = self.take().await?;
+        w.trace(|r| r.describe(MessageType::Response, Some(id)));
+        Ok(RpcResponseSink::new(w, id))
+    }
+}
+
+/// Waits in line for exclusive ownership of a `SharedWriter`
+struct TakeWriter<W> {
+    shared: SharedWriter<W>,
+    key: Option<usize>,
+}
+
+impl<W: AsyncWrite + Unpin> Future for TakeWriter<W> {
+    type Output = IoResult<SharedWriterGuard<W>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let shared = &this.shared;
+        shared
+            .slot
+            .borrow_mut()
+            .poll_take(&mut this.key, cx)
+            .map_ok(|w| SharedWriterGuard {
+                writer: Some(w),
+                shared: shared.clone(),
+                partial: false,
+            })
+    }
+}
+
+impl<W> Drop for TakeWriter<W> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            self.shared.slot.borrow_mut().cancel(key);
+        }
+    }
+}
+
+/// Exclusive ownership of a `SharedWriter`. The writer is handed to the next
+/// message in line when this is dropped.
+///
+/// Dropping it after writing part of a message, without `release()`, leaves
+/// the connection in the middle of a message. The writer is poisoned
+/// instead: it's dropped, and every later `take()` fails.
+pub struct SharedWriterGuard<W> {
+    writer: Option<W>,
+    shared: SharedWriter<W>,
+    /// Written to since the last complete message
+    partial: bool,
+}
+
+impl<W: AsyncWrite + Unpin> SharedWriterGuard<W> {
+    /// Mark the message complete, flush the writer, then hand it to the
+    /// next message in line
+    pub async fn release(mut self) -> IoResult<()> {
+        self.partial = false;
+        self.flush().await
+    }
+
+    /// Access the underlying writer directly, e.g. to inspect what has been
+    /// written so far
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
+    /// Pass the tracing recorder, if any, to `f`
+    fn trace(&self, f: impl FnOnce(&mut Recorder)) {
+        if let Some(recorder) = &mut *self.shared.recorder.borrow_mut() {
+            f(recorder);
+        }
+    }
+
+    async fn write_abandoned(&mut self) -> IoResult<()> {
+        loop {
+            let id = self.shared.slot.borrow_mut().abandoned.pop_front();
+            match id {
+                Some(id) => {
+                    self.trace(|r| {
+                        r.begin();
+                        r.describe(MessageType::Response, Some(id));
+                    });
+                    let error = ApplicationError::internal(ABANDONED_ERROR);
+                    let w = RpcResponseSink::new(&mut *self, id).error().await?;
+                    error.to_msgpack(w).await?.finish().await?;
+                    self.partial = false;
+                    self.trace(Recorder::end);
+                }
+                None => break Ok(()),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SharedWriterGuard<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let poll = W::poll_write(Pin::new(self.writer.as_mut().unwrap()), cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.partial |= n > 0;
+            self.trace(|r| r.bytes(&buf[..n]));
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_flush(Pin::new(self.writer.as_mut().unwrap()), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_close(Pin::new(self.writer.as_mut().unwrap()), cx)
+    }
+}
+
+impl<W> Drop for SharedWriterGuard<W> {
+    fn drop(&mut self) {
+        if let Some(w) = self.writer.take() {
+            if let Some(recorder) = &mut *self.shared.recorder.borrow_mut() {
+                recorder.end();
+            }
+            let mut slot = self.shared.slot.borrow_mut();
+            if self.partial {
+                slot.poison();
+            } else {
+                slot.give_back(w);
+            }
+        }
+    }
+}
+
+/// Handle for responding to a request. Remembers the request id and holds a
+/// reference to the writer responses are sent on.
+///
+/// If dropped without responding, an error response is queued on the
+/// `SharedWriter` so the peer isn't left waiting on the id.
+pub struct RpcResponder<W> {
+    writer: Option<SharedWriter<W>>,
+    id: u32,
+}
+
+impl<W: AsyncWrite + Unpin> RpcResponder<W> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Wait for the writer and take responsibility for the response away
+    /// from `Drop`
+    async fn take(&mut self) -> IoResult<SharedWriterGuard<W>> {
+        let guard = self.writer.as_ref().unwrap().take().await?;
+        self.writer = None;
+        guard.trace(|r| r.describe(MessageType::Response, Some(self.id)));
+        Ok(guard)
+    }
+
+    /// Write a successful response, returning a sink for the result value
+    pub async fn respond_ok(mut self) -> IoResult<MsgPackSink<SharedWriterGuard<W>>> {
+        let guard = self.take().await?;
+        RpcResponseSink::new(guard, self.id).ok().await
+    }
+
+    /// Write an error response, returning a sink for the error value
+    pub async fn respond_err(
+        mut self,
+    ) -> IoResult<MsgPackSink<RpcResultSink<SharedWriterGuard<W>>>> {
+        let guard = self.take().await?;
+        RpcResponseSink::new(guard, self.id).error().await
+    }
+
+    /// Write an error response in the conventional `[code, message, data]`
+    /// encoding
+    pub async fn respond_error(self, error: &ApplicationError) -> IoResult<()> {
+        let w = error.to_msgpack(self.respond_err().await?).await?;
+        w.finish().await?.release().await
+    }
+
+    /// Write a response from dynamic `Value`s, with a nil result in the error
+    /// case
+    pub async fn respond(self, result: Result<&Value, &Value>) -> IoResult<()> {
+        match result {
+            Ok(val) => {
+                self.respond_ok()
+                    .await?
+                    .write_value(val)
+                    .await?
+                    .release()
+                    .await
+            }
+            Err(err) => {
+                self.respond_err()
+                    .await?
+                    .write_value(err)
+                    .await?
+                    .finish()
+                    .await?
+                    .release()
+                    .await
+            }
+        }
+    }
+}
+
+impl<W> Drop for RpcResponder<W> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.slot.borrow_mut().abandon(self.id);
+        }
+    }
+}
+
+/// `SharedWriter` that can be used from multiple threads. Ownership of the
+/// writer is managed the same way, with the slot protected by a mutex.
+pub struct SyncSharedWriter<W>(Arc<Mutex<WriterSlot<W>>>);
+
+impl<W> Clone for SyncSharedWriter<W> {
+    fn clone(&self) -> Self {
+        SyncSharedWriter(self.0.clone())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> SyncSharedWriter<W> {
+    /// Share a writer with no limit on the number of queued messages
+    pub fn new(writer: W) -> Self {
+        SyncSharedWriter(Arc::new(Mutex::new(WriterSlot::new(writer, None))))
+    }
+
+    /// Share a writer, letting at most `max_queued` messages wait in line
+    /// for it. Once the line is full, `take()` waits for room in it.
+    pub fn with_queue_limit(writer: W, max_queued: usize) -> Self {
+        SyncSharedWriter(Arc::new(Mutex::new(WriterSlot::new(
+            writer,
+            Some(max_queued),
+        ))))
+    }
+
+    /// Number of messages in line for the writer, not counting any waiting
+    /// for room in the line
+    pub fn queued(&self) -> usize {
+        self.0.lock().unwrap().waiters.len()
+    }
+
+    /// Wait for exclusive ownership of the writer
+    pub fn take(&self) -> SyncTakeWriter<W> {
+        SyncTakeWriter {
+            shared: self.clone(),
+            key: None,
+        }
+    }
+
+    pub async fn request(
+        &self,
+        id: u32,
+        method: &str,
+        num_params: u32,
+    ) -> IoResult<RpcParamsSink<SyncSharedWriterGuard<W>>> {
+        let w = self.take().await?;
+        RpcRequestSink::new(w, id).method(method, num_params).await
+    }
+
+    pub async fn notify(
+        &self,
+        method: &str,
+        num_params: u32,
+    ) -> IoResult<RpcParamsSink<SyncSharedWriterGuard<W>>> {
+        let w = self.take().await?;
+        RpcNotifySink::new(w).method(method, num_params).await
+    }
+
+    pub async fn response(&self, id: u32) -> IoResult<RpcResponseSink<SyncSharedWriterGuard<W>>> {
+        self.take().await.map(|w| RpcResponseSink::new(w, id))
+    }
+}
+
+/// Future returned by `SyncSharedWriter::take()`
+pub struct SyncTakeWriter<W> {
+    shared: SyncSharedWriter<W>,
+    key: Option<usize>,
+}
+
+impl<W: AsyncWrite + Unpin> Future for SyncTakeWriter<W> {
+    type Output = IoResult<SyncSharedWriterGuard<W>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let shared = &this.shared;
+        let poll = shared.0.lock().unwrap().poll_take(&mut this.key, cx);
+        poll.map_ok(|w| SyncSharedWriterGuard {
+            writer: Some(w),
+            shared: shared.clone(),
+            partial: false,
+        })
+    }
+}
+
+impl<W> Drop for SyncTakeWriter<W> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            if let Ok(mut slot) = self.shared.0.lock() {
+                slot.cancel(key);
+            }
+        }
+    }
+}
+
+/// Exclusive ownership of a `SyncSharedWriter`. The writer is handed to the
+/// next message in line when this is dropped, or poisoned if that's partway
+/// through a message, like `SharedWriterGuard`.
+pub struct SyncSharedWriterGuard<W> {
+    writer: Option<W>,
+    shared: SyncSharedWriter<W>,
+    partial: bool,
+}
+
+impl<W: AsyncWrite + Unpin> SyncSharedWriterGuard<W> {
+    /// Mark the message complete, flush the writer, then hand it to the
+    /// next message in line
+    pub async fn release(mut self) -> IoResult<()> {
+        self.partial = false;
+        self.flush().await
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for SyncSharedWriterGuard<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let poll = W::poll_write(Pin::new(self.writer.as_mut().unwrap()), cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.partial |= n > 0;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_flush(Pin::new(self.writer.as_mut().unwrap()), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        W::poll_close(Pin::new(self.writer.as_mut().unwrap()), cx)
+    }
+}
+
+impl<W> Drop for SyncSharedWriterGuard<W> {
+    fn drop(&mut self) {
+        if let Some(w) = self.writer.take() {
+            if let Ok(mut slot) = self.shared.0.lock() {
+                if self.partial {
+                    slot.poison();
+                } else {
+                    slot.give_back(w);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::decode::{RpcMessage, RpcStream};
+    use futures::task::noop_waker_ref;
+    use std::io::Cursor;
+
+    /// Writes at most one byte at a time, returning `Pending` in between so
+    /// concurrent messages get a chance to interleave
+    struct TrickleWriter {
+        buf: Vec<u8>,
+        ready: bool,
+    }
+
+    impl AsyncWrite for TrickleWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            if self.ready {
+                self.ready = false;
+                self.buf.push(buf[0]);
+                Poll::Ready(Ok(1))
+            } else {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn concurrent_messages() {
+        let shared = SharedWriter::new(TrickleWriter {
+            buf: Vec::new(),
+            ready: true,
+        });
+
+        async fn request(shared: SharedWriter<TrickleWriter>) -> IoResult<()> {
+            let params = shared.request(7, "summon", 1).await?;
+            params
+                .last()
+                .unwrap()
+                .write_str("husker")
+                .await?
+                .release()
+                .await
+        }
+
+        async fn notify(shared: SharedWriter<TrickleWriter>) -> IoResult<()> {
+            let params = shared.notify("redraw", 1).await?;
+            params
+                .last()
+                .unwrap()
+                .write_str("flush")
+                .await?
+                .release()
+                .await
+        }
+
+        let mut pool = futures::executor::LocalPool::new();
+        let (r1, r2) = pool.run_until(future::join(
+            request(shared.clone()),
+            notify(shared.clone()),
+        ));
+        r1.unwrap();
+        r2.unwrap();
+
+        let buf = shared.slot.borrow_mut().writer.take().unwrap().buf;
+        async fn read_messages(stream: RpcStream<Cursor<Vec<u8>>>) -> IoResult<()> {
+            let stream = match stream.next().await? {
+                RpcMessage::Request(req) => {
+                    assert_eq!(req.id(), 7);
+                    let (method, params) = req.method().await?.into_string().await?;
+                    assert_eq!(method, "summon");
+                    let params = params.params().await?;
+                    let (param, stream) = params
+                        .last()
+                        .unwrap()
+                        .decode()
+                        .await?
+                        .into_string()
+                        .unwrap()
+                        .into_string()
+                        .await?;
+                    assert_eq!(param, "husker");
+                    stream
+                }
+                _ => panic!("Wrong message type"),
+            };
+            match stream.next().await? {
+                RpcMessage::Notify(notify) => {
+                    let (method, _params) = notify.method().await?.into_string().await?;
+                    assert_eq!(method, "redraw");
+                }
+                _ => panic!("Wrong message type"),
+            }
+            Ok(())
+        }
+        pool.run_until(read_messages(RpcStream::new(Cursor::new(buf))))
+            .unwrap();
+    }
+
+    #[test]
+    fn queue_limit() {
+        let shared = SharedWriter::with_queue_limit(Vec::new(), 1);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        let guard = shared.take();
+        futures::pin_mut!(guard);
+        let guard = match guard.poll(&mut cx) {
+            Poll::Ready(guard) => guard.unwrap(),
+            Poll::Pending => panic!("writer should be available"),
+        };
+
+        let mut queued = shared.take().boxed_local();
+        assert!(queued.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(shared.queued(), 1);
+
+        // The line is full, so this one waits to join it
+        let mut parked = shared.take().boxed_local();
+        assert!(parked.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(shared.queued(), 1);
+
+        // Releasing the writer hands it to the queued message, making room
+        // in line for the parked one
+        drop(guard);
+        let guard = match queued.as_mut().poll(&mut cx) {
+            Poll::Ready(guard) => guard.unwrap(),
+            Poll::Pending => panic!("queued message should get the writer"),
+        };
+        assert_eq!(shared.queued(), 1);
+        assert!(parked.as_mut().poll(&mut cx).is_pending());
+
+        drop(guard);
+        assert!(parked.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(shared.queued(), 0);
+    }
+
+    #[test]
+    fn dropped_mid_message() {
+        let shared = SharedWriter::new(Vec::new());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut pool = futures::executor::LocalPool::new();
+
+        // A finished message without release() leaves the writer usable
+        let guard = pool.run_until(shared.take()).unwrap();
+        drop(guard);
+
+        let params = pool.run_until(shared.notify("redraw", 1)).unwrap();
+        let mut queued = shared.take().boxed_local();
+        assert!(queued.as_mut().poll(&mut cx).is_pending());
+
+        // Dropping the params leaves the message unfinished
+        drop(params);
+        match queued.as_mut().poll(&mut cx) {
+            Poll::Ready(Err(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+            _ => panic!("queued message should fail"),
+        }
+        let err = pool.run_until(shared.take()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn sync_writer_is_send() {
+        fn assert_send<T: Send + Sync>() {}
+        assert_send::<SyncSharedWriter<Vec<u8>>>();
+        assert_send::<SyncSharedWriterGuard<Vec<u8>>>();
+    }
+
+    #[test]
+    fn responder() {
+        let call1 = Value::Array(vec![
+            0.into(),
+            1.into(),
+            "ping".into(),
+            Value::Array(vec![]),
+        ]);
+        let call2 = Value::Array(vec![
+            0.into(),
+            2.into(),
+            "hang".into(),
+            Value::Array(vec![]),
+        ]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &call1).unwrap();
+        rmpv::encode::write_value(&mut buf, &call2).unwrap();
+
+        async fn serve(
+            stream: RpcStream<Cursor<Vec<u8>>>,
+            shared: SharedWriter<Vec<u8>>,
+        ) -> IoResult<()> {
+            let stream = match stream.next().await? {
+                RpcMessage::Request(req) => {
+                    let responder = req.responder(&shared);
+                    let stream = req
+                        .method()
+                        .await?
+                        .skip()
+                        .await?
+                        .params()
+                        .await?
+                        .skip()
+                        .await?;
+                    responder
+                        .respond_ok()
+                        .await?
+                        .write_str("pong")
+                        .await?
+                        .release()
+                        .await?;
+                    stream
+                }
+                _ => panic!("Wrong message type"),
+            };
+            match stream.next().await? {
+                RpcMessage::Request(req) => {
+                    let responder = req.responder(&shared);
+                    assert_eq!(responder.id(), 2);
+                    drop(responder);
+                }
+                _ => panic!("Wrong message type"),
+            }
+            shared.respond_abandoned().await
+        }
+
+        let shared = SharedWriter::new(Vec::new());
+        futures::executor::LocalPool::new()
+            .run_until(serve(RpcStream::new(Cursor::new(buf)), shared.clone()))
+            .unwrap();
+
+        let mut expected = Vec::new();
+        let resp1 = Value::Array(vec![1.into(), 1.into(), Value::Nil, "pong".into()]);
+        let error = ApplicationError::internal(ABANDONED_ERROR).to_value();
+        let resp2 = Value::Array(vec![1.into(), 2.into(), error, Value::Nil]);
+        rmpv::encode::write_value(&mut expected, &resp1).unwrap();
+        rmpv::encode::write_value(&mut expected, &resp2).unwrap();
+        assert_eq!(shared.slot.borrow_mut().writer.take().unwrap(), expected);
+    }
+}
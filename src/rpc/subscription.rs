@@ -0,0 +1,264 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use rmpv::Value;
+
+/// Count of the notifications queued for a subscription, shared between
+/// its `Sender` and the `Subscription` reading them
+#[derive(Default)]
+struct Queue {
+    len: usize,
+    /// Publishers waiting for the subscription to be read from or dropped
+    waiting: Vec<Waker>,
+}
+
+impl Queue {
+    fn wake(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Sending end of a subscription. The registry and `publish()` share it, so
+/// that waiting for room doesn't hold the registry borrowed.
+struct Sender {
+    tx: mpsc::UnboundedSender<Vec<Value>>,
+    queue: Rc<RefCell<Queue>>,
+}
+
+impl Sender {
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Wait until fewer than `capacity` notifications are queued, then queue
+    /// one more. Fails if the subscription was dropped.
+    async fn send(
+        &self,
+        capacity: Option<usize>,
+        params: Vec<Value>,
+    ) -> Result<(), mpsc::SendError> {
+        future::poll_fn(|cx| {
+            let mut queue = self.queue.borrow_mut();
+            match capacity {
+                Some(capacity) if queue.len >= capacity.max(1) && !self.is_closed() => {
+                    if !queue.waiting.iter().any(|w| w.will_wake(cx.waker())) {
+                        queue.waiting.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+                _ => Poll::Ready(()),
+            }
+        })
+        .await;
+        self.tx
+            .unbounded_send(params)
+            .map_err(|e| e.into_send_error())?;
+        self.queue.borrow_mut().len += 1;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    senders: HashMap<String, Vec<Rc<Sender>>>,
+    /// Length of the longest method name ever subscribed to
+    max_method_len: usize,
+    /// Notifications queued for each subscription before `publish()` waits
+    capacity: Option<usize>,
+}
+
+/// Notification methods that callers have subscribed to, shared between the
+/// `RpcServer` publishing notifications and the callers holding
+/// `Subscription` streams
+#[derive(Clone, Default)]
+pub struct Subscriptions(Rc<RefCell<Registry>>);
+
+impl Subscriptions {
+    /// Create a registry whose subscriptions queue any number of
+    /// notifications
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue at most `capacity` notifications, and at least one, for each
+    /// subscription, including existing ones, or any number if `None`.
+    /// `publish()` waits for a full subscription to be read from, so a
+    /// subscriber that stops reading its stream without dropping it stalls
+    /// whatever is publishing, such as a session's read loop.
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.0.borrow_mut().capacity = capacity;
+    }
+
+    /// Stream of the params of every `method` notification published from now
+    /// on. Dropping the stream unsubscribes.
+    pub fn subscribe(&self, method: &str) -> Subscription {
+        let mut registry = self.0.borrow_mut();
+        let (tx, rx) = mpsc::unbounded();
+        let queue = Rc::new(RefCell::new(Queue::default()));
+        registry.max_method_len = registry.max_method_len.max(method.len());
+        registry
+            .senders
+            .entry(method.into())
+            .or_default()
+            .push(Rc::new(Sender {
+                tx,
+                queue: queue.clone(),
+            }));
+        Subscription {
+            method: method.into(),
+            rx,
+            queue,
+        }
+    }
+
+    /// Whether any stream is still subscribed to `method`
+    pub fn is_subscribed(&self, method: &str) -> bool {
+        let mut registry = self.0.borrow_mut();
+        match registry.senders.get_mut(method) {
+            Some(senders) => {
+                senders.retain(|tx| !tx.is_closed());
+                if senders.is_empty() {
+                    registry.senders.remove(method);
+                    false
+                } else {
+                    true
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Send a notification's params to every stream subscribed to `method`,
+    /// waiting for room in any that are full. Returns false if there were
+    /// none.
+    pub async fn publish(&self, method: &str, params: Vec<Value>) -> bool {
+        if !self.is_subscribed(method) {
+            return false;
+        }
+        let (senders, capacity) = {
+            let registry = self.0.borrow();
+            (registry.senders[method].clone(), registry.capacity)
+        };
+        let (last, rest) = senders.split_last().unwrap();
+        for tx in rest {
+            let _ = tx.send(capacity, params.clone()).await;
+        }
+        let _ = last.send(capacity, params).await;
+        true
+    }
+
+    /// Names longer than this can't have been subscribed to
+    pub fn max_method_len(&self) -> usize {
+        self.0.borrow().max_method_len
+    }
+}
+
+/// Stream of the params of each notification with a given method name. Ends
+/// when the server publishing the notifications is dropped.
+pub struct Subscription {
+    method: String,
+    rx: mpsc::UnboundedReceiver<Vec<Value>>,
+    queue: Rc<RefCell<Queue>>,
+}
+
+impl Subscription {
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Vec<Value>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let params = self.rx.poll_next_unpin(cx);
+        if let Poll::Ready(Some(_)) = params {
+            let mut queue = self.queue.borrow_mut();
+            queue.len -= 1;
+            queue.wake();
+        }
+        params
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Release any publisher waiting for room
+        self.rx.close();
+        self.queue.borrow_mut().wake();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn publish() {
+        use futures::executor::block_on;
+
+        let subs = Subscriptions::new();
+        let first = subs.subscribe("redraw");
+        let second = subs.subscribe("redraw");
+        assert!(!block_on(subs.publish("other", vec![])));
+        assert!(block_on(subs.publish("redraw", vec![1.into()])));
+        drop(second);
+        assert!(block_on(subs.publish("redraw", vec![2.into()])));
+
+        let third = subs.subscribe("redraw");
+        drop(subs);
+        let received = futures::executor::block_on(future::join(
+            first.collect::<Vec<_>>(),
+            third.collect::<Vec<_>>(),
+        ));
+        assert_eq!(received.0, vec![vec![1.into()], vec![2.into()]]);
+        assert!(received.1.is_empty());
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let subs = Subscriptions::new();
+        let sub = subs.subscribe("redraw");
+        assert!(subs.is_subscribed("redraw"));
+        drop(sub);
+        assert!(!subs.is_subscribed("redraw"));
+        assert!(!futures::executor::block_on(subs.publish("redraw", vec![])));
+    }
+
+    #[test]
+    fn backpressure() {
+        use futures::task::noop_waker_ref;
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let subs = Subscriptions::new();
+        let mut sub = subs.subscribe("redraw");
+        // Applies to subscriptions that already exist
+        subs.set_capacity(Some(2));
+        let publish = |n: i64| subs.publish("redraw", vec![n.into()]).boxed_local();
+        assert_eq!(publish(1).poll_unpin(&mut cx), Poll::Ready(true));
+        assert_eq!(publish(2).poll_unpin(&mut cx), Poll::Ready(true));
+
+        // The third waits for the subscriber to catch up
+        let mut third = publish(3);
+        assert!(third.poll_unpin(&mut cx).is_pending());
+        assert_eq!(
+            sub.poll_next_unpin(&mut cx),
+            Poll::Ready(Some(vec![1.into()]))
+        );
+        assert_eq!(third.poll_unpin(&mut cx), Poll::Ready(true));
+        drop(third);
+
+        // Dropping the subscription releases a waiting publisher
+        let mut fourth = publish(4);
+        assert!(fourth.poll_unpin(&mut cx).is_pending());
+        drop(sub);
+        assert_eq!(fourth.poll_unpin(&mut cx), Poll::Ready(true));
+    }
+}
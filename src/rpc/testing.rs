@@ -0,0 +1,379 @@
+//! Scriptable msgpack-rpc peer for testing code that talks to one.
+//!
+//! Tests declare the calls and notifications they expect, in order, and the
+//! responses and notifications the peer sends back. The peer runs over an
+//! in-memory `duplex()` connection, and once it finishes it panics with a
+//! report of any expectations that weren't met and any messages it didn't
+//! expect. A peer that's dropped without being run panics too, if it had
+//! any expectations.
+//!
+//! Only built for this crate's tests, or with the `testing` feature.
+
+use std::collections::VecDeque;
+
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+use rmpv::Value;
+
+use crate::rpc::client::RpcResult;
+use crate::rpc::decode::{RpcMessage, RpcStream};
+use crate::rpc::error::{ApplicationError, INVALID_REQUEST};
+use crate::rpc::server::params_value;
+use crate::rpc::shared::SharedWriter;
+use crate::rpc::transport::{duplex, DuplexStream};
+use crate::MsgPackOption;
+
+/// Buffer size of each direction of the mock connection
+const CAPACITY: usize = 64 * 1024;
+
+/// Checks the params of an incoming message
+type Matcher = Box<dyn Fn(&[Value]) -> bool>;
+
+/// An expected call or notification from the code under test
+pub struct Expectation {
+    method: String,
+    description: String,
+    params: Option<Matcher>,
+    response: RpcResult,
+}
+
+impl Expectation {
+    fn new(method: &str) -> Self {
+        Expectation {
+            method: method.into(),
+            description: method.into(),
+            params: None,
+            response: Ok(Value::Nil),
+        }
+    }
+
+    /// Only match if the params are exactly `params`
+    pub fn with_params(&mut self, params: Vec<Value>) -> &mut Self {
+        self.description = format!("{} {}", self.method, Value::Array(params.clone()));
+        self.params = Some(Box::new(move |p| p == &params[..]));
+        self
+    }
+
+    /// Only match if `matcher` accepts the params
+    pub fn matching(&mut self, matcher: impl Fn(&[Value]) -> bool + 'static) -> &mut Self {
+        self.description = format!("{} (custom matcher)", self.method);
+        self.params = Some(Box::new(matcher));
+        self
+    }
+
+    /// Respond to the call with `result`. Calls return nil by default.
+    pub fn returns(&mut self, result: Value) -> &mut Self {
+        self.response = Ok(result);
+        self
+    }
+
+    /// Respond to the call with `error`
+    pub fn fails(&mut self, error: Value) -> &mut Self {
+        self.response = Err(error);
+        self
+    }
+
+    fn matches(&self, method: &str, params: &[Value]) -> bool {
+        self.method == method && self.params.as_ref().is_none_or(|m| m(params))
+    }
+}
+
+enum Step {
+    Call(Expectation),
+    ExpectNotify(Expectation),
+    Notify(String, Vec<Value>),
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        match self {
+            Step::Call(e) => format!("call {}", e.description),
+            Step::ExpectNotify(e) => format!("notification {}", e.description),
+            Step::Notify(method, params) => {
+                format!(
+                    "sending notification {} {}",
+                    method,
+                    Value::Array(params.clone())
+                )
+            }
+        }
+    }
+}
+
+/// Peer that plays back a script of expected messages and replies
+pub struct MockPeer {
+    /// Taken by `run()`, along with the steps
+    io: Option<DuplexStream>,
+    steps: VecDeque<Step>,
+}
+
+impl MockPeer {
+    /// Create a peer, along with the end of the connection to hand to the
+    /// code under test
+    pub fn new() -> (Self, DuplexStream) {
+        let (io, other) = duplex(CAPACITY);
+        let peer = MockPeer {
+            io: Some(io),
+            steps: VecDeque::new(),
+        };
+        (peer, other)
+    }
+
+    /// Expect a request for `method` next
+    pub fn expect_call(&mut self, method: &str) -> &mut Expectation {
+        self.steps.push_back(Step::Call(Expectation::new(method)));
+        match self.steps.back_mut() {
+            Some(Step::Call(e)) => e,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Expect a notification for `method` next. Any response set on the
+    /// expectation is ignored.
+    pub fn expect_notify(&mut self, method: &str) -> &mut Expectation {
+        self.steps
+            .push_back(Step::ExpectNotify(Expectation::new(method)));
+        match self.steps.back_mut() {
+            Some(Step::ExpectNotify(e)) => e,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Send a notification once the expectations before it are met
+    pub fn notify(&mut self, method: &str, params: Vec<Value>) -> &mut Self {
+        self.steps.push_back(Step::Notify(method.into(), params));
+        self
+    }
+
+    /// Serve the script until the other end closes the connection.
+    ///
+    /// Requests that don't match the next expectation get an
+    /// `INVALID_REQUEST` error response. Unless the connection fails, this
+    /// panics when done if anything in the script was left over or any
+    /// message was unexpected.
+    pub async fn run(mut self) -> IoResult<()> {
+        let io = self.io.take().unwrap();
+        // The report takes over checking the steps, disarming our own drop
+        let mut report = Report {
+            steps: std::mem::take(&mut self.steps),
+            unexpected: Vec::new(),
+            armed: true,
+        };
+        let (reader, writer) = io.split();
+        let result = report
+            .serve(RpcStream::new(reader), SharedWriter::new(writer))
+            .await;
+        if result.is_err() {
+            report.armed = false;
+        }
+        result
+    }
+}
+
+/// What's left of the script, and what happened that wasn't in it
+struct Report {
+    steps: VecDeque<Step>,
+    unexpected: Vec<String>,
+    armed: bool,
+}
+
+impl Report {
+    async fn serve<R, W>(
+        &mut self,
+        mut stream: RpcStream<R>,
+        writer: SharedWriter<W>,
+    ) -> IoResult<()>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        loop {
+            while let Some(Step::Notify(..)) = self.steps.front() {
+                if let Some(Step::Notify(method, params)) = self.steps.pop_front() {
+                    send_notify(&writer, &method, &params).await?;
+                }
+            }
+            stream = match stream.try_next().await? {
+                Some(RpcMessage::Request(req)) => {
+                    let responder = req.responder(&writer);
+                    let (method, params) = req.method().await?.into_string().await?;
+                    let (params, stream) = params_value(params.params().await?).await?;
+                    match self.steps.front() {
+                        Some(Step::Call(e)) if e.matches(&method, &params) => {
+                            let response = match self.steps.pop_front() {
+                                Some(Step::Call(e)) => e.response,
+                                _ => unreachable!(),
+                            };
+                            responder.respond(response.as_ref()).await?;
+                        }
+                        _ => {
+                            let call = format!("call {} {}", method, Value::Array(params));
+                            let error = ApplicationError::new(
+                                INVALID_REQUEST,
+                                format!("unexpected {}", call),
+                            );
+                            self.unexpected.push(call);
+                            responder.respond_error(&error).await?;
+                        }
+                    }
+                    stream
+                }
+                Some(RpcMessage::Notify(n)) => {
+                    let (method, params) = n.method().await?.into_string().await?;
+                    let (params, stream) = params_value(params.params().await?).await?;
+                    match self.steps.front() {
+                        Some(Step::ExpectNotify(e)) if e.matches(&method, &params) => {
+                            self.steps.pop_front();
+                        }
+                        _ => self.unexpected.push(format!(
+                            "notification {} {}",
+                            method,
+                            Value::Array(params)
+                        )),
+                    }
+                    stream
+                }
+                Some(RpcMessage::Response(resp)) => {
+                    self.unexpected.push(format!("response to {}", resp.id()));
+                    resp.skip().await?
+                }
+                None => return Ok(()),
+            };
+        }
+    }
+}
+
+async fn send_notify<W>(writer: &SharedWriter<W>, method: &str, params: &[Value]) -> IoResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut sink = writer.notify(method, params.len() as u32).await?;
+    for param in params {
+        sink = match sink.next() {
+            MsgPackOption::Some(m) => m.write_value(param).await?,
+            MsgPackOption::End(_) => unreachable!(),
+        };
+    }
+    sink.next().unwrap_end().release().await
+}
+
+/// Panic with a report of what didn't go to script, if anything
+fn check(steps: &VecDeque<Step>, unexpected: &[String]) {
+    if std::thread::panicking() {
+        return;
+    }
+    let mut problems = Vec::new();
+    for step in steps {
+        problems.push(format!("unmet: {}", step.describe()));
+    }
+    for message in unexpected {
+        problems.push(format!("unexpected: {}", message));
+    }
+    if !problems.is_empty() {
+        panic!("mock peer script failed:\n{}", problems.join("\n"));
+    }
+}
+
+impl Drop for MockPeer {
+    fn drop(&mut self) {
+        check(&self.steps, &[]);
+    }
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        if self.armed {
+            check(&self.steps, &self.unexpected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::client::RpcClient;
+    use crate::rpc::server::RpcServer;
+    use crate::rpc::session::RpcSession;
+    use crate::rpc::subscription::Subscription;
+    use futures::io::WriteHalf;
+
+    type Client = RpcClient<WriteHalf<DuplexStream>>;
+
+    /// Run `test` against the peer in a session subscribed to `redraw`, then
+    /// close the connection and return the peer's result
+    fn run<F: Future<Output = IoResult<()>>>(
+        peer: MockPeer,
+        io: DuplexStream,
+        test: impl FnOnce(Client, Subscription) -> F,
+    ) -> IoResult<()> {
+        let session = RpcSession::from_duplex(io, RpcServer::new());
+        let shutdown = session.shutdown_handle();
+        let test = test(session.client(), session.subscribe("redraw"));
+        let test = async move {
+            let result = test.await;
+            shutdown.shutdown();
+            result
+        };
+        let (test, session, peer) = futures::executor::LocalPool::new().run_until(future::join3(
+            test,
+            session.run(),
+            peer.run(),
+        ));
+        test.unwrap();
+        session.unwrap();
+        peer
+    }
+
+    #[test]
+    fn scripted() {
+        let (mut peer, io) = MockPeer::new();
+        peer.expect_call("add")
+            .with_params(vec![1.into(), 2.into()])
+            .returns(3.into());
+        peer.expect_notify("log");
+        peer.notify("redraw", vec!["line".into()]);
+        run(peer, io, |client, mut redraw| async move {
+            let sum = client.call_value("add", &[1.into(), 2.into()]).await?;
+            assert_eq!(sum, Ok(3.into()));
+            let params = client.writer().notify("log", 0).await?;
+            params.next().unwrap_end().release().await?;
+            assert_eq!(redraw.next().await, Some(vec!["line".into()]));
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "unmet: call mul")]
+    fn unmet() {
+        let (mut peer, io) = MockPeer::new();
+        peer.expect_call("add").returns(3.into());
+        peer.expect_call("mul");
+        run(peer, io, |client, _| async move {
+            client.call_value("add", &[]).await?.unwrap();
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "unmet: call add (custom matcher)")]
+    fn never_run() {
+        let (mut peer, _io) = MockPeer::new();
+        peer.expect_call("add").matching(|p| p.len() == 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected: call add [1, 2]")]
+    fn unexpected() {
+        let (mut peer, io) = MockPeer::new();
+        peer.expect_call("add").matching(|p| p.len() == 3);
+        run(peer, io, |client, _| async move {
+            let error = client.call_value("add", &[1.into(), 2.into()]).await?;
+            let error = ApplicationError::from_value(&error.unwrap_err()).unwrap();
+            assert_eq!(error.code, INVALID_REQUEST);
+            Ok(())
+        })
+        .unwrap();
+    }
+}
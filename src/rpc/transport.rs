@@ -0,0 +1,391 @@
+//! Ready-made transports for running RPC sessions on one machine.
+//!
+//! Each transport is a `DuplexStream`, so it can be split into the reader
+//! for an `RpcStream` and the writer for a `SharedWriter`, or handed whole to
+//! `RpcSession::from_duplex`. Transports over blocking `std::io` handles,
+//! like stdio, child processes and Unix sockets, copy to and from the
+//! duplex on two background threads.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use futures::executor::block_on;
+use futures::io::Error as IoError;
+use futures::io::ErrorKind;
+use futures::io::Result as IoResult;
+use futures::prelude::*;
+
+/// Buffer size of each direction of a bridged transport
+const BRIDGE_CAPACITY: usize = 64 * 1024;
+
+/// One direction of a `DuplexStream` pair
+struct Pipe {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// The writing end was closed or dropped
+    write_closed: bool,
+    /// The reading end was dropped
+    read_closed: bool,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Arc<Mutex<Pipe>> {
+        Arc::new(Mutex::new(Pipe {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+            read_waker: None,
+            write_waker: None,
+            write_closed: false,
+            read_closed: false,
+        }))
+    }
+
+    fn close_write(&mut self) {
+        self.write_closed = true;
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close_read(&mut self) {
+        self.read_closed = true;
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// One end of an in-memory connection created by `duplex()`. Bytes written
+/// to one end are read from the other. Closing or dropping an end gives the
+/// other end EOF.
+pub struct DuplexStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// Create both ends of an in-memory connection. Each direction buffers up
+/// to `capacity` bytes before writes wait for the other end to read.
+///
+/// `testing::MockPeer` talks to the code under test over one of these.
+pub fn duplex(capacity: usize) -> (DuplexStream, DuplexStream) {
+    assert!(capacity > 0, "duplex capacity must be positive");
+    let a = Pipe::new(capacity);
+    let b = Pipe::new(capacity);
+    (
+        DuplexStream {
+            read: a.clone(),
+            write: b.clone(),
+        },
+        DuplexStream { read: b, write: a },
+    )
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.write_closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        if let Some(waker) = pipe.write_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.read_closed {
+            return Poll::Ready(Err(IoError::new(
+                ErrorKind::BrokenPipe,
+                "other end of duplex closed",
+            )));
+        }
+        if pipe.write_closed {
+            return Poll::Ready(Err(IoError::new(
+                ErrorKind::NotConnected,
+                "duplex closed for writing",
+            )));
+        }
+        let n = buf.len().min(pipe.capacity - pipe.buf.len());
+        if n == 0 && !buf.is_empty() {
+            pipe.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        pipe.buf.extend(&buf[..n]);
+        if let Some(waker) = pipe.read_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        self.write.lock().unwrap().close_write();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.write.lock().unwrap().close_write();
+        self.read.lock().unwrap().close_read();
+    }
+}
+
+/// Bridge a blocking reader and writer onto a `DuplexStream`.
+///
+/// One thread reads from `reader` until EOF or an error, which the duplex
+/// sees as EOF. The other writes to `writer` until the duplex is closed or
+/// dropped, and then drops `writer`. The reading thread can only notice the
+/// duplex has gone once its next read returns, so until `reader` has
+/// something to read or reaches EOF, the thread stays blocked and keeps
+/// `reader` open after the duplex is dropped.
+pub fn bridge<R, W>(mut reader: R, mut writer: W) -> DuplexStream
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let (io, other) = duplex(BRIDGE_CAPACITY);
+    let (mut from_duplex, mut to_duplex) = other.split();
+    thread::spawn(move || {
+        let mut buf = vec![0; BRIDGE_CAPACITY];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::debug!("transport read failed: {}", e);
+                    break;
+                }
+            };
+            if block_on(to_duplex.write_all(&buf[..n])).is_err() {
+                // The duplex was dropped
+                break;
+            }
+        }
+        let _ = block_on(to_duplex.close());
+    });
+    thread::spawn(move || {
+        let mut buf = vec![0; BRIDGE_CAPACITY];
+        loop {
+            let n = match block_on(from_duplex.read(&mut buf)) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Err(e) = writer.write_all(&buf[..n]).and_then(|_| writer.flush()) {
+                log::debug!("transport write failed: {}", e);
+                break;
+            }
+        }
+    });
+    io
+}
+
+/// Transport over this process's stdin and stdout, for a plugin talking to
+/// its host. Nothing else should use stdin or stdout while it's open.
+///
+/// The thread reading stdin is leaked: it's left blocked reading stdin
+/// until the host closes it, which may be never, and any input it reads
+/// after the transport is dropped is lost.
+pub fn stdio() -> DuplexStream {
+    bridge(std::io::stdin(), std::io::stdout())
+}
+
+/// Spawn `command` and talk to it over its stdin and stdout. The child's
+/// stdin is closed when the returned transport is closed or dropped, but
+/// waiting for it to exit is left to the caller.
+pub fn spawn(command: &mut Command) -> IoResult<(Child, DuplexStream)> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    Ok((child, bridge(stdout, stdin)))
+}
+
+#[cfg(unix)]
+pub use self::unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use std::io::{Result as IoResult, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::thread;
+
+    use futures::channel::mpsc;
+    use futures::prelude::*;
+
+    use super::{bridge, DuplexStream};
+
+    /// Shuts down the writing side of the socket once the bridge is done with
+    /// it, since the reading side keeps the socket open
+    struct SocketWriter(UnixStream);
+
+    impl Write for SocketWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Drop for SocketWriter {
+        fn drop(&mut self) {
+            let _ = self.0.shutdown(Shutdown::Write);
+        }
+    }
+
+    /// Transport over a connected Unix domain socket
+    pub fn unix_stream(stream: UnixStream) -> IoResult<DuplexStream> {
+        let writer = SocketWriter(stream.try_clone()?);
+        Ok(bridge(stream, writer))
+    }
+
+    /// Connect to the Unix domain socket at `path`
+    pub fn connect_unix(path: impl AsRef<Path>) -> IoResult<DuplexStream> {
+        unix_stream(UnixStream::connect(path)?)
+    }
+
+    /// Listen on the Unix domain socket at `path`, which must not already
+    /// exist
+    pub fn bind_unix(path: impl AsRef<Path>) -> IoResult<UnixIncoming> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        let (tx, rx) = mpsc::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let conn = stream.and_then(unix_stream);
+                if tx.unbounded_send(conn).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(UnixIncoming { rx, path })
+    }
+
+    /// Stream of connections accepted by `bind_unix()`. Dropping it removes
+    /// the socket, and connects to it first so the listening thread wakes up
+    /// and stops.
+    pub struct UnixIncoming {
+        rx: mpsc::UnboundedReceiver<IoResult<DuplexStream>>,
+        path: PathBuf,
+    }
+
+    impl Stream for UnixIncoming {
+        type Item = IoResult<DuplexStream>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+            Pin::new(&mut self.rx).poll_next(cx)
+        }
+    }
+
+    impl Drop for UnixIncoming {
+        fn drop(&mut self) {
+            // The listening thread stops once it accepts a connection it
+            // can't pass on
+            self.rx.close();
+            let _ = UnixStream::connect(&self.path);
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duplex_pair() {
+        let (mut a, mut b) = duplex(4);
+        let write = async move {
+            a.write_all(b"hello, world").await?;
+            a.close().await?;
+            let mut reply = Vec::new();
+            a.read_to_end(&mut reply).await?;
+            Ok::<_, IoError>(reply)
+        };
+        let read = async move {
+            let mut received = Vec::new();
+            b.read_to_end(&mut received).await?;
+            b.write_all(b"bye").await?;
+            Ok::<_, IoError>(received)
+        };
+        let (reply, received) =
+            futures::executor::LocalPool::new().run_until(future::join(write, read));
+        assert_eq!(received.unwrap(), b"hello, world");
+        assert_eq!(reply.unwrap(), b"bye");
+    }
+
+    /// Send a notification and read back the one echoed by the other end
+    async fn echo_notify(io: DuplexStream) -> IoResult<String> {
+        use crate::rpc::decode::{RpcMessage, RpcStream};
+        use crate::rpc::shared::SharedWriter;
+
+        let (reader, writer) = io.split();
+        let writer = SharedWriter::new(writer);
+        let params = writer.notify("echo", 0).await?;
+        params.next().unwrap_end().release().await?;
+        match RpcStream::new(reader).next().await? {
+            RpcMessage::Notify(n) => Ok(n.method().await?.into_string().await?.0),
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn child_process() {
+        let (mut child, io) = spawn(&mut Command::new("cat")).unwrap();
+        assert_eq!(block_on(echo_notify(io)).unwrap(), "echo");
+        // Dropping the transport closes cat's stdin
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        let path = std::env::temp_dir().join(format!("rmp-futures-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut incoming = bind_unix(&path).unwrap();
+        let client = connect_unix(&path).unwrap();
+        let echo = async {
+            let conn = incoming.next().await.unwrap()?;
+            let (reader, mut writer) = conn.split();
+            reader.copy_into(&mut writer).await?;
+            writer.close().await
+        };
+        let (method, echoed) = block_on(future::join(echo_notify(client), echo));
+        assert_eq!(method.unwrap(), "echo");
+        echoed.unwrap();
+
+        drop(incoming);
+        assert!(!path.exists());
+        assert!(connect_unix(&path).is_err());
+    }
+}